@@ -0,0 +1,42 @@
+use std::path::PathBuf;
+
+use glob::glob;
+
+use crate::sensors::iio::{read_attr, write_attr};
+
+/// Turns keyboard backlights (`/sys/class/leds/*::kbd_backlight`) off and
+/// back on to the brightness they had before.
+#[derive(Default)]
+pub struct KeyboardBacklight {
+    saved: Option<Vec<(PathBuf, String)>>,
+}
+
+impl KeyboardBacklight {
+    pub fn disable(&mut self) -> Result<(), String> {
+        if self.saved.is_some() {
+            return Ok(());
+        }
+        let mut saved = vec![];
+        let mut result = Ok(());
+        for led in glob("/sys/class/leds/*::kbd_backlight").unwrap().flatten() {
+            let brightness = led.join("brightness");
+            match read_attr(&brightness).and_then(|v| write_attr(&brightness, "0").map(|_| v)) {
+                Ok(value) => saved.push((brightness, value)),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        // Keep what was turned off so far, so it is restored later
+        self.saved = Some(saved);
+        result
+    }
+
+    pub fn restore(&mut self) -> Result<(), String> {
+        for (brightness, value) in self.saved.take().unwrap_or_default() {
+            write_attr(&brightness, &value)?;
+        }
+        Ok(())
+    }
+}
@@ -1,14 +1,105 @@
-use crate::Orientation;
+use crate::{DeviceState, Orientation};
+use glob::Pattern;
 use wayland_client::protocol::wl_output::Transform;
 
 pub trait DisplayManager {
     /// Change the orientation of the target display.
-    fn change_rotation_state(&mut self, new_state: &Orientation);
+    fn change_rotation_state(&mut self, new_state: &Orientation) -> Result<(), String>;
 
     /// Get the current transformation of the target display.
     fn get_rotation_state(&mut self) -> Result<Transform, String>;
+
+    /// Adjust input devices to how the device is used, e.g. disable the
+    /// keyboard in tablet mode.
+    fn update_device_state(&mut self, _state: &DeviceState) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Handle events that arrived since the last call, like inputs being
+    /// added. Returns the new transformation if the target display was
+    /// rotated by someone else. Called on every reading.
+    fn poll(&mut self) -> Result<Option<Transform>, String> {
+        Ok(None)
+    }
 }
 
+/// Selects touch and pen inputs by name or identifier globs. Without any
+/// include patterns every input is included.
+pub struct InputFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl InputFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, String> {
+        Ok(InputFilter {
+            include: compile_patterns(include)?,
+            exclude: compile_patterns(exclude)?,
+        })
+    }
+
+    /// Whether an input known under any of `names` is selected.
+    pub fn matches(&self, names: &[&str]) -> bool {
+        let any = |patterns: &[Pattern]| {
+            patterns
+                .iter()
+                .any(|p| names.iter().any(|name| p.matches(name)))
+        };
+        (self.include.is_empty() || any(&self.include)) && !any(&self.exclude)
+    }
+
+    /// Whether inputs were selected explicitly rather than left to detection.
+    pub fn has_include(&self) -> bool {
+        !self.include.is_empty()
+    }
+}
+
+/// The transform last set by rot8 or reported to it. Lets backends tell
+/// rotations by someone else apart from their own.
+#[derive(Default)]
+pub struct KnownTransform(Option<Transform>);
+
+impl KnownTransform {
+    pub fn set(&mut self, transform: Transform) -> Transform {
+        self.0 = Some(transform);
+        transform
+    }
+
+    /// Records `current`, returning it if it is not the known transform.
+    pub fn changed(&mut self, current: Transform) -> Option<Transform> {
+        if self.0 == Some(current) {
+            return None;
+        }
+        self.0 = Some(current);
+        Some(current)
+    }
+}
+
+pub fn compile_patterns(patterns: &[String]) -> Result<Vec<Pattern>, String> {
+    patterns
+        .iter()
+        .map(|p| Pattern::new(p).map_err(|e| format!("Invalid input pattern '{}': {}", p, e)))
+        .collect()
+}
+
+pub mod kbd_backlight;
 pub mod sway;
+pub mod sway_ipc;
 pub mod wlroots;
+pub mod x11;
 pub mod xorg;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_other_transforms_count_as_changes() {
+        let mut known = KnownTransform::default();
+        assert_eq!(known.changed(Transform::Normal), Some(Transform::Normal));
+        assert_eq!(known.changed(Transform::Normal), None);
+        known.set(Transform::_90);
+        assert_eq!(known.changed(Transform::_90), None);
+        assert_eq!(known.changed(Transform::_180), Some(Transform::_180));
+    }
+}
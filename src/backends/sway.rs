@@ -1,78 +1,466 @@
-use std::process::Command;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 
-use serde_json::Value;
+use glob::Pattern;
 use wayland_client::protocol::wl_output::Transform;
 
-use crate::Orientation;
+use crate::config::DisableRule;
+use crate::{DeviceState, Orientation};
 
-use super::{wlroots::WaylandBackend, DisplayManager};
+use super::{
+    kbd_backlight::KeyboardBacklight,
+    sway_ipc::{SwayIpc, INPUT_EVENT, OUTPUT_EVENT},
+    wlroots::WaylandBackend,
+    DisplayManager, InputFilter,
+};
+
+/// Sway input types that follow the screen rotation.
+const TOUCH_TYPES: [&str; 2] = ["touch", "tablet_tool"];
 
 pub struct SwayBackend {
     wayland_backend: WaylandBackend,
-    manage_keyboard: bool,
+    ipc: Option<SwayIpc>,
+    target_display: String,
+    touch_filter: InputFilter,
+    inputs: InputPolicy,
+    /// Input and output events from sway.
+    events: Option<EventWatcher>,
+    /// The state last applied to the inputs.
+    state: Option<DeviceState>,
+    backlight: KeyboardBacklight,
 }
 
 impl SwayBackend {
-    pub fn new(wayland_backend: WaylandBackend, manage_keyboard: bool) -> Self {
-        SwayBackend {
+    pub fn new(
+        wayland_backend: WaylandBackend,
+        target_display: &str,
+        touch_filter: InputFilter,
+        disable_rules: Vec<DisableRule>,
+        keep_inputs: Vec<Pattern>,
+    ) -> Self {
+        let ipc = SwayIpc::connect()
+            .map_err(|e| eprintln!("{}, inputs will not be managed", e))
+            .ok();
+        let mut backend = SwayBackend {
             wayland_backend,
-            manage_keyboard,
+            ipc,
+            target_display: target_display.into(),
+            touch_filter,
+            inputs: InputPolicy {
+                rules: disable_rules,
+                keep_inputs,
+                disabled: BTreeMap::new(),
+            },
+            events: EventWatcher::new()
+                .map_err(|e| eprintln!("{}, new inputs will not be managed", e))
+                .ok(),
+            state: None,
+            backlight: KeyboardBacklight::default(),
+        };
+        if let Err(e) = backend.map_touch_inputs() {
+            eprintln!("Unable to map touch inputs: {}", e);
+        }
+        backend
+    }
+
+    /// Map touchscreens and pens to the target display, so that sway
+    /// rotates their input along with it.
+    fn map_touch_inputs(&mut self) -> Result<(), String> {
+        let ipc = match &mut self.ipc {
+            Some(ipc) => ipc,
+            None => return Ok(()),
+        };
+
+        let mut commands: Vec<String> = vec![];
+        for input in ipc.get_inputs()? {
+            let input_type = input["type"].as_str().unwrap_or_default();
+            let identifier = input["identifier"].as_str().unwrap_or_default();
+            let name = input["name"].as_str().unwrap_or_default();
+            let map = format!(
+                "input \"{}\" map_to_output {}",
+                identifier, self.target_display
+            );
+            if TOUCH_TYPES.contains(&input_type)
+                && self.touch_filter.matches(&[identifier, name])
+                && !commands.contains(&map)
+            {
+                commands.push(map);
+            }
         }
+        ipc.run_commands(&commands)
     }
+}
+
+/// Disables inputs while rules apply, and gives them back the `events`
+/// state they had before.
+struct InputPolicy {
+    rules: Vec<DisableRule>,
+    /// Inputs that are never disabled.
+    keep_inputs: Vec<Pattern>,
+    /// Inputs disabled by rot8, with the `events` state to restore.
+    disabled: BTreeMap<String, String>,
+}
+
+impl InputPolicy {
+    /// Disable the inputs selected by the rules active in `state`, and give
+    /// back the others.
+    fn update(&mut self, ipc: &mut SwayIpc, state: &DeviceState) -> Result<(), String> {
+        let active: Vec<&DisableRule> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.applies(state))
+            .collect();
+
+        let patterns: Vec<Pattern> = active
+            .iter()
+            .flat_map(|rule| rule.identifiers.iter())
+            .filter_map(|identifier| Pattern::new(identifier).ok())
+            .collect();
+
+        let mut commands = vec![];
+        let mut present = vec![];
+        for input in ipc.get_inputs()? {
+            let identifier = input["identifier"].as_str().unwrap_or_default().to_string();
+            let name = input["name"].as_str().unwrap_or_default();
+            let input_type = input["type"].as_str().unwrap_or_default();
+            if present.contains(&identifier) {
+                continue;
+            }
+            let selected = (active
+                .iter()
+                .any(|rule| rule.types.iter().any(|t| t == input_type))
+                || patterns.iter().any(|p| p.matches(&identifier)))
+                && !self
+                    .keep_inputs
+                    .iter()
+                    .any(|p| p.matches(&identifier) || p.matches(name));
 
-    fn get_keyboards() -> Vec<String> {
-        let raw_inputs = String::from_utf8(
-            Command::new("swaymsg")
-                .arg("-t")
-                .arg("get_inputs")
-                .arg("--raw")
-                .output()
-                .expect("Swaymsg get inputs command failed")
-                .stdout,
-        )
-        .unwrap();
-
-        let mut keyboards = vec![];
-        let deserialized: Vec<Value> =
-            serde_json::from_str(&raw_inputs).expect("Unable to deserialize swaymsg JSON output");
-        for output in deserialized {
-            let input_type = output["type"].as_str().unwrap();
-            if input_type == "keyboard" {
-                keyboards.push(output["identifier"].to_string());
+            if selected && !self.disabled.contains_key(&identifier) {
+                let events = input["libinput"]["send_events"]
+                    .as_str()
+                    .unwrap_or("enabled")
+                    .to_string();
+                commands.push(format!("input \"{}\" events disabled", identifier));
+                self.disabled.insert(identifier.clone(), events);
+            } else if !selected {
+                if let Some(events) = self.disabled.remove(&identifier) {
+                    commands.push(format!("input \"{}\" events {}", identifier, events));
+                }
             }
+            present.push(identifier);
         }
+        // Forget inputs that went away while disabled
+        self.disabled
+            .retain(|identifier, _| present.contains(identifier));
 
-        keyboards
+        ipc.run_commands(&commands)
+    }
+
+    /// Whether a rule active in `state` turns off the keyboard backlight.
+    fn backlight_off(&self, state: &DeviceState) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.applies(state) && rule.types.iter().any(|t| t == "kbd_backlight"))
+    }
+
+    /// Give back every input disabled so far.
+    fn restore(&mut self, ipc: &mut SwayIpc) -> Result<(), String> {
+        let commands: Vec<String> = self
+            .disabled
+            .iter()
+            .map(|(identifier, events)| format!("input \"{}\" events {}", identifier, events))
+            .collect();
+        self.disabled.clear();
+        ipc.run_commands(&commands)
     }
 }
 
 impl DisplayManager for SwayBackend {
-    fn change_rotation_state(&mut self, new_state: &Orientation) {
-        self.wayland_backend.change_rotation_state(new_state);
+    fn change_rotation_state(&mut self, new_state: &Orientation) -> Result<(), String> {
+        self.wayland_backend.change_rotation_state(new_state)
+    }
 
-        if !self.manage_keyboard {
-            return;
-        }
+    fn get_rotation_state(&mut self) -> Result<Transform, String> {
+        self.wayland_backend.get_rotation_state()
+    }
+
+    fn update_device_state(&mut self, state: &DeviceState) -> Result<(), String> {
+        self.state = Some(*state);
+        let result = match &mut self.ipc {
+            Some(ipc) => self.inputs.update(ipc, state),
+            None => Ok(()),
+        };
 
-        let keyboard_state = if new_state.wayland_state == Transform::Normal {
-            "enabled"
+        // Writing the brightness usually needs extra permissions, which must
+        // not keep the inputs from being updated
+        let backlight = if self.inputs.backlight_off(state) {
+            self.backlight.disable()
         } else {
-            "disabled"
+            self.backlight.restore()
         };
-        for keyboard in &SwayBackend::get_keyboards() {
-            Command::new("swaymsg")
-                .arg("input")
-                .arg(keyboard)
-                .arg("events")
-                .arg(keyboard_state)
-                .spawn()
-                .expect("Swaymsg keyboard command failed to start")
-                .wait()
-                .expect("Swaymsg keyboard command wait failed");
+        if let Err(e) = backlight {
+            eprintln!("Unable to switch the keyboard backlight: {}", e);
         }
+
+        result
     }
 
-    fn get_rotation_state(&mut self) -> Result<Transform, String> {
-        self.wayland_backend.get_rotation_state()
+    fn poll(&mut self) -> Result<Option<Transform>, String> {
+        let (input_added, output_changed) = match &self.events {
+            Some(events) => events
+                .receiver
+                .try_iter()
+                .fold((false, false), |(i, o), event| match event {
+                    SwayEvent::InputAdded => (true, o),
+                    SwayEvent::OutputChanged => (i, true),
+                }),
+            // Without events, ask the compositor every time
+            None => (false, true),
+        };
+        if input_added {
+            // Apply the mapping and the current policy to the new inputs
+            self.map_touch_inputs()?;
+            if let Some(state) = self.state {
+                self.update_device_state(&state)?;
+            }
+        }
+        if output_changed {
+            self.wayland_backend.poll()
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Drop for SwayBackend {
+    /// Give back inputs disabled by this backend, e.g. when the configuration
+    /// is reloaded.
+    fn drop(&mut self) {
+        let _ = self.backlight.restore();
+        if let Some(ipc) = &mut self.ipc {
+            let _ = self.inputs.restore(ipc);
+        }
+    }
+}
+
+enum SwayEvent {
+    InputAdded,
+    OutputChanged,
+}
+
+/// Listens for sway `input` and `output` events on a separate connection,
+/// until dropped.
+struct EventWatcher {
+    receiver: Receiver<SwayEvent>,
+    ipc: SwayIpc,
+    stopping: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EventWatcher {
+    fn new() -> Result<Self, String> {
+        let mut ipc = SwayIpc::connect()?;
+        ipc.subscribe(&["input", "output"])?;
+        let handle = ipc.try_clone()?;
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let (sender, receiver) = mpsc::channel();
+        let thread_stopping = stopping.clone();
+        let thread = thread::spawn(move || loop {
+            let event = match ipc.read_event() {
+                Ok((OUTPUT_EVENT, _)) => SwayEvent::OutputChanged,
+                Ok((INPUT_EVENT, payload)) if payload["change"] == "added" => SwayEvent::InputAdded,
+                Ok(_) => continue,
+                Err(e) => {
+                    if !thread_stopping.load(Ordering::Relaxed) {
+                        eprintln!("Lost sway events: {}", e);
+                    }
+                    break;
+                }
+            };
+            if sender.send(event).is_err() {
+                break;
+            }
+        });
+        Ok(EventWatcher {
+            receiver,
+            ipc: handle,
+            stopping,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for EventWatcher {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        self.ipc.shutdown();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::io::{Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::time::Duration;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rot8-{}-{}.sock", name, std::process::id()))
+    }
+
+    /// Read one IPC message, or `None` once the client hung up.
+    fn read_message(stream: &mut UnixStream) -> Option<(u32, String)> {
+        let mut header = [0u8; 14];
+        stream.read_exact(&mut header).ok()?;
+        let length = u32::from_ne_bytes([header[6], header[7], header[8], header[9]]);
+        let message_type = u32::from_ne_bytes([header[10], header[11], header[12], header[13]]);
+        let mut payload = vec![0u8; length as usize];
+        stream.read_exact(&mut payload).ok()?;
+        Some((message_type, String::from_utf8(payload).unwrap()))
+    }
+
+    fn write_message(stream: &mut UnixStream, message_type: u32, payload: &Value) {
+        let payload = payload.to_string();
+        let mut message = b"i3-ipc".to_vec();
+        message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+        message.extend_from_slice(&message_type.to_ne_bytes());
+        message.extend_from_slice(payload.as_bytes());
+        stream.write_all(&message).unwrap();
+    }
+
+    /// A stand-in for sway answering GET_INPUTS with `inputs` and reporting
+    /// the payload of every RUN_COMMAND.
+    fn fake_sway(path: &Path, inputs: Value) -> Receiver<String> {
+        let listener = UnixListener::bind(path).unwrap();
+        let (sender, commands) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while let Some((message_type, payload)) = read_message(&mut stream) {
+                let reply = match message_type {
+                    0 => {
+                        let results: Vec<Value> = payload
+                            .split("; ")
+                            .map(|_| json!({"success": true}))
+                            .collect();
+                        sender.send(payload).unwrap();
+                        Value::Array(results)
+                    }
+                    100 => inputs.clone(),
+                    _ => json!({"success": true}),
+                };
+                write_message(&mut stream, message_type, &reply);
+            }
+        });
+        commands
+    }
+
+    fn input(identifier: &str, input_type: &str, send_events: &str) -> Value {
+        json!({
+            "identifier": identifier,
+            "name": identifier.rsplit(':').next().unwrap().replace('_', " "),
+            "type": input_type,
+            "libinput": {"send_events": send_events},
+        })
+    }
+
+    fn state(tablet_mode: bool) -> DeviceState {
+        DeviceState {
+            orientation: Transform::Normal,
+            posture: None,
+            tablet_mode,
+        }
+    }
+
+    #[test]
+    fn disabled_inputs_get_their_events_state_back() {
+        let path = socket_path("sway-inputs");
+        let commands = fake_sway(
+            &path,
+            json!([
+                input("1:1:AT_Translated_Set_2_keyboard", "keyboard", "enabled"),
+                input(
+                    "2:7:SynPS/2_Synaptics_TouchPad",
+                    "touchpad",
+                    "disabled_on_external_mouse"
+                ),
+                // Disabled by the user, and to stay that way
+                input("1267:12345:ELAN_Touchpad", "touchpad", "disabled"),
+                input("1133:16495:Logitech_K400", "keyboard", "enabled"),
+                input("1267:10752:ELAN_Touchscreen", "touch", "enabled"),
+            ]),
+        );
+        let mut ipc = SwayIpc::connect_to(&path).unwrap();
+        let mut policy = InputPolicy {
+            rules: vec![DisableRule::in_tablet_mode(&["keyboard", "touchpad"])],
+            keep_inputs: vec![Pattern::new("*Logitech*").unwrap()],
+            disabled: BTreeMap::new(),
+        };
+        let next = || commands.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        policy.update(&mut ipc, &state(false)).unwrap();
+        policy.update(&mut ipc, &state(true)).unwrap();
+        assert_eq!(
+            next(),
+            "input \"1:1:AT_Translated_Set_2_keyboard\" events disabled; \
+             input \"2:7:SynPS/2_Synaptics_TouchPad\" events disabled; \
+             input \"1267:12345:ELAN_Touchpad\" events disabled"
+        );
+
+        // Nothing changes while the rule keeps applying
+        policy.update(&mut ipc, &state(true)).unwrap();
+        policy.update(&mut ipc, &state(false)).unwrap();
+        assert_eq!(
+            next(),
+            "input \"1:1:AT_Translated_Set_2_keyboard\" events enabled; \
+             input \"2:7:SynPS/2_Synaptics_TouchPad\" events disabled_on_external_mouse; \
+             input \"1267:12345:ELAN_Touchpad\" events disabled"
+        );
+        assert!(matches!(
+            commands.recv_timeout(Duration::from_millis(100)),
+            Err(RecvTimeoutError::Timeout)
+        ));
+
+        drop(ipc);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn event_watcher_stops_when_dropped() {
+        let path = socket_path("sway-events");
+        let listener = UnixListener::bind(&path).unwrap();
+        std::env::set_var("SWAYSOCK", &path);
+
+        // A stand-in for sway that acknowledges the subscription, then
+        // stays silent until the client hangs up
+        let (closed_sender, closed) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (message_type, _) = read_message(&mut stream).unwrap();
+            write_message(&mut stream, message_type, &json!({"success": true}));
+
+            assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+            closed_sender.send(()).unwrap();
+        });
+
+        let watcher = EventWatcher::new().unwrap();
+        assert!(matches!(
+            watcher.receiver.recv_timeout(Duration::from_millis(100)),
+            Err(RecvTimeoutError::Timeout)
+        ));
+        drop(watcher);
+
+        closed.recv_timeout(Duration::from_secs(5)).unwrap();
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,148 @@
+use std::env;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use serde_json::Value;
+
+const MAGIC: &[u8; 6] = b"i3-ipc";
+
+const RUN_COMMAND: u32 = 0;
+const SUBSCRIBE: u32 = 2;
+const GET_INPUTS: u32 = 100;
+
+pub const OUTPUT_EVENT: u32 = 1;
+pub const INPUT_EVENT: u32 = 21;
+
+/// A connection to sway's IPC socket, speaking the i3 binary protocol:
+/// the magic string, then the payload length and message type as native
+/// endian u32s, then a JSON payload.
+pub struct SwayIpc {
+    stream: UnixStream,
+}
+
+impl SwayIpc {
+    /// Connect to the socket named by `$SWAYSOCK`.
+    pub fn connect() -> Result<Self, String> {
+        let path = env::var("SWAYSOCK").map_err(|_| "SWAYSOCK is not set".to_string())?;
+        SwayIpc::connect_to(Path::new(&path))
+    }
+
+    /// Connect to the socket at `path`.
+    pub fn connect_to(path: &Path) -> Result<Self, String> {
+        let stream = UnixStream::connect(path)
+            .map_err(|e| format!("Unable to connect to sway at {}: {}", path.display(), e))?;
+        Ok(SwayIpc { stream })
+    }
+
+    /// Another handle to the same connection.
+    pub fn try_clone(&self) -> Result<Self, String> {
+        let stream = self
+            .stream
+            .try_clone()
+            .map_err(|e| format!("Unable to clone sway IPC connection: {}", e))?;
+        Ok(SwayIpc { stream })
+    }
+
+    /// Close the connection, waking up anyone waiting for a reply or event.
+    pub fn shutdown(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    /// Send a message and wait for its reply.
+    fn message(&mut self, message_type: u32, payload: &str) -> Result<Value, String> {
+        let mut message = Vec::with_capacity(14 + payload.len());
+        message.extend_from_slice(MAGIC);
+        message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+        message.extend_from_slice(&message_type.to_ne_bytes());
+        message.extend_from_slice(payload.as_bytes());
+        self.stream
+            .write_all(&message)
+            .map_err(|e| format!("Unable to send sway IPC message: {}", e))?;
+
+        let (reply_type, reply) = self.read_message()?;
+        if reply_type != message_type {
+            return Err(format!(
+                "Unexpected sway IPC reply type {} to message type {}",
+                reply_type, message_type
+            ));
+        }
+        Ok(reply)
+    }
+
+    fn read_message(&mut self) -> Result<(u32, Value), String> {
+        let mut header = [0u8; 14];
+        self.stream
+            .read_exact(&mut header)
+            .map_err(|e| format!("Unable to read sway IPC reply: {}", e))?;
+        if &header[..6] != MAGIC {
+            return Err("Invalid sway IPC reply".to_string());
+        }
+        let length = u32::from_ne_bytes([header[6], header[7], header[8], header[9]]);
+        let reply_type = u32::from_ne_bytes([header[10], header[11], header[12], header[13]]);
+
+        let mut payload = vec![0u8; length as usize];
+        self.stream
+            .read_exact(&mut payload)
+            .map_err(|e| format!("Unable to read sway IPC reply: {}", e))?;
+        let value = serde_json::from_slice(&payload)
+            .map_err(|e| format!("Invalid sway IPC reply: {}", e))?;
+        Ok((reply_type, value))
+    }
+
+    /// Run several commands in one message, failing if any of them failed.
+    pub fn run_commands(&mut self, commands: &[String]) -> Result<(), String> {
+        if commands.is_empty() {
+            return Ok(());
+        }
+        let reply = self.message(RUN_COMMAND, &commands.join("; "))?;
+        let results = reply
+            .as_array()
+            .ok_or_else(|| "Invalid sway command reply".to_string())?;
+
+        let errors: Vec<String> = results
+            .iter()
+            .zip(commands)
+            .filter(|(result, _)| !result["success"].as_bool().unwrap_or(false))
+            .map(|(result, command)| {
+                format!(
+                    "'{}': {}",
+                    command,
+                    result["error"].as_str().unwrap_or("unknown error")
+                )
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Sway commands failed: {}", errors.join(", ")))
+        }
+    }
+
+    /// Subscribe to events like `input`, to be read with `read_event`.
+    pub fn subscribe(&mut self, events: &[&str]) -> Result<(), String> {
+        let payload = serde_json::to_string(events).unwrap();
+        let reply = self.message(SUBSCRIBE, &payload)?;
+        if reply["success"].as_bool() == Some(true) {
+            Ok(())
+        } else {
+            Err(format!("Unable to subscribe to sway events {:?}", events))
+        }
+    }
+
+    /// Wait for the next event after subscribing. Event types have the high
+    /// bit set, which is stripped here.
+    pub fn read_event(&mut self) -> Result<(u32, Value), String> {
+        let (event_type, event) = self.read_message()?;
+        Ok((event_type & !(1 << 31), event))
+    }
+
+    /// All input devices, as returned by GET_INPUTS.
+    pub fn get_inputs(&mut self) -> Result<Vec<Value>, String> {
+        match self.message(GET_INPUTS, "")? {
+            Value::Array(inputs) => Ok(inputs),
+            _ => Err("Invalid sway inputs reply".to_string()),
+        }
+    }
+}
@@ -13,11 +13,12 @@ use wayland_protocols_wlr::output_management::v1::client::{
 
 use crate::Orientation;
 
-use super::DisplayManager;
+use super::{DisplayManager, KnownTransform};
 
 pub struct WaylandBackend {
     state: AppData,
     event_queue: EventQueue<AppData>,
+    known_transform: KnownTransform,
 }
 
 impl WaylandBackend {
@@ -40,37 +41,55 @@ impl WaylandBackend {
             .as_ref()
             .ok_or("Compositor does not support wlr_output_management_v1.")?;
 
-        Ok(WaylandBackend { state, event_queue })
+        Ok(WaylandBackend {
+            state,
+            event_queue,
+            known_transform: KnownTransform::default(),
+        })
     }
 
     /// Receive (and send) all buffered messages across the wayland socket.
-    fn read_socket(&mut self) {
+    fn read_socket(&mut self) -> Result<(), String> {
         self.event_queue
             .roundtrip(&mut self.state)
-            .expect("Failed to read display changes.");
+            .map(|_| ())
+            .map_err(|e| format!("Failed to read display changes: {}", e))
     }
 
     /// Send all buffered messages across the wayland socket.
     /// Slightly cheaper than `read_socket`.
-    fn write_socket(&self) {
+    fn write_socket(&self) -> Result<(), String> {
         self.event_queue
             .flush()
-            .expect("Failed to apply display changes.");
+            .map_err(|e| format!("Failed to apply display changes: {}", e))
     }
 }
 
 impl DisplayManager for WaylandBackend {
-    fn change_rotation_state(&mut self, new_state: &Orientation) {
-        self.read_socket();
+    fn change_rotation_state(&mut self, new_state: &Orientation) -> Result<(), String> {
+        self.read_socket()?;
         self.state.update_configuration(new_state.wayland_state);
-        self.write_socket();
+        self.write_socket()?;
+        self.known_transform.set(new_state.wayland_state);
+        Ok(())
     }
 
     fn get_rotation_state(&mut self) -> Result<Transform, String> {
-        self.read_socket();
-        self.state
+        self.read_socket()?;
+        let transform = self
+            .state
             .current_transform
-            .ok_or("Failed to get current display rotation".into())
+            .ok_or("Failed to get current display rotation")?;
+        Ok(self.known_transform.set(transform))
+    }
+
+    fn poll(&mut self) -> Result<Option<Transform>, String> {
+        // The compositor sends the head's new transform whenever it changes
+        self.read_socket()?;
+        Ok(self
+            .state
+            .current_transform
+            .and_then(|transform| self.known_transform.changed(transform)))
     }
 }
 
@@ -83,7 +102,7 @@ struct AppData {
     queue_handle: QueueHandle<AppData>,
 }
 
-/// Public interface
+// Public interface
 
 impl AppData {
     pub fn new(event_queue: &mut EventQueue<AppData>, target_display_name: String) -> Self {
@@ -111,7 +130,7 @@ impl AppData {
     }
 }
 
-/// Event handlers
+// Event handlers
 
 impl Dispatch<wl_registry::WlRegistry, ()> for AppData {
     fn event(
@@ -167,14 +186,14 @@ impl Dispatch<ZwlrOutputHeadV1, ()> for AppData {
         _: &QueueHandle<AppData>,
     ) {
         match event {
-            zwlr_output_head_v1::Event::Name { name } => {
-                if name == state.target_display_name {
-                    // println!("Found target display: {}", name);
-                    state.target_head = Some(head.clone());
-                }
+            zwlr_output_head_v1::Event::Name { name } if name == state.target_display_name => {
+                // println!("Found target display: {}", name);
+                state.target_head = Some(head.clone());
             }
-            zwlr_output_head_v1::Event::Transform { transform } => {
-                state.current_transform = Some(transform.into_result().unwrap())
+            zwlr_output_head_v1::Event::Transform { transform }
+                if state.target_head.as_ref() == Some(head) =>
+            {
+                state.current_transform = transform.into_result().ok()
             }
             _ => {}
         }
@@ -0,0 +1,658 @@
+use std::fmt;
+use std::path::Path;
+
+use wayland_client::protocol::wl_output::Transform;
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::errors::{ConnectError, ConnectionError, ReplyError};
+use x11rb::protocol::randr::{self, ConnectionExt as _, Crtc, GetCrtcInfoReply, Rotation};
+use x11rb::protocol::xinput::{
+    self, ConnectionExt as _, DeviceClassData, DeviceId, DeviceType, TouchMode,
+    XIChangePropertyAux, XIDeviceInfo, XIGetPropertyItems,
+};
+use x11rb::protocol::xproto::{Atom, AtomEnum, ConnectionExt as _, PropMode, Window};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+
+use super::{DisplayManager, InputFilter, KnownTransform};
+use crate::matrix::fit_to_output;
+use crate::sensors::hwdb::udev_properties;
+use crate::Orientation;
+
+/// Errors from talking to the X server.
+#[derive(Debug)]
+pub enum X11Error {
+    Connect(ConnectError),
+    Connection(ConnectionError),
+    Reply(ReplyError),
+    MissingExtension(&'static str),
+    OutputNotFound(String),
+    OutputDisabled(String),
+    /// The server refused the new CRTC configuration.
+    ConfigFailed(String),
+}
+
+impl fmt::Display for X11Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            X11Error::Connect(e) => write!(f, "Unable to connect to the X server: {}", e),
+            X11Error::Connection(e) => write!(f, "Lost connection to the X server: {}", e),
+            X11Error::Reply(e) => write!(f, "X request failed: {}", e),
+            X11Error::MissingExtension(name) => {
+                write!(f, "The X server does not support {}", name)
+            }
+            X11Error::OutputNotFound(name) => write!(f, "Display {} not found", name),
+            X11Error::OutputDisabled(name) => write!(f, "Display {} is not enabled", name),
+            X11Error::ConfigFailed(name) => write!(f, "Unable to rotate display {}", name),
+        }
+    }
+}
+
+impl From<ConnectError> for X11Error {
+    fn from(e: ConnectError) -> Self {
+        X11Error::Connect(e)
+    }
+}
+
+impl From<ConnectionError> for X11Error {
+    fn from(e: ConnectionError) -> Self {
+        X11Error::Connection(e)
+    }
+}
+
+impl From<ReplyError> for X11Error {
+    fn from(e: ReplyError) -> Self {
+        X11Error::Reply(e)
+    }
+}
+
+/// The CRTC driving the target output, as found in the current configuration.
+struct Target {
+    crtc: Crtc,
+    info: GetCrtcInfoReply,
+    config_timestamp: u32,
+    /// All other enabled CRTCs.
+    others: Vec<(Crtc, GetCrtcInfoReply)>,
+}
+
+/// XInput2 atoms used to find and map touch devices.
+struct InputAtoms {
+    matrix: Atom,
+    float: Atom,
+    device_node: Atom,
+}
+
+/// Rotates an output with RandR and maps touchscreens with XInput2, talking
+/// to the X server directly.
+pub struct X11Backend {
+    conn: RustConnection,
+    root: Window,
+    /// Physical size of a pixel, to keep the DPI when resizing the screen.
+    mm_per_pixel: (f64, f64),
+    target_display: String,
+    touch_filter: InputFilter,
+    /// Only set when XInput2 is available.
+    input_atoms: Option<InputAtoms>,
+    known_transform: KnownTransform,
+}
+
+impl X11Backend {
+    pub fn new(display: &str, touch_filter: InputFilter) -> Result<Self, X11Error> {
+        X11Backend::connect_to(None, display, touch_filter)
+    }
+
+    /// Like `new`, on the given X server instead of `$DISPLAY`.
+    fn connect_to(
+        server: Option<&str>,
+        display: &str,
+        touch_filter: InputFilter,
+    ) -> Result<Self, X11Error> {
+        let (conn, screen_num) = x11rb::connect(server)?;
+        let screen = &conn.setup().roots[screen_num];
+        let root = screen.root;
+        let mm_per_pixel = (
+            screen.width_in_millimeters as f64 / screen.width_in_pixels as f64,
+            screen.height_in_millimeters as f64 / screen.height_in_pixels as f64,
+        );
+
+        if conn
+            .extension_information(randr::X11_EXTENSION_NAME)?
+            .is_none()
+        {
+            return Err(X11Error::MissingExtension("RandR"));
+        }
+        conn.randr_query_version(1, 3)?.reply()?;
+        conn.randr_select_input(root, randr::NotifyMask::SCREEN_CHANGE)?
+            .check()?;
+
+        let input_atoms = if conn
+            .extension_information(xinput::X11_EXTENSION_NAME)?
+            .is_some()
+        {
+            conn.xinput_xi_query_version(2, 2)?.reply()?;
+            let atom = |name: &[u8]| -> Result<Atom, X11Error> {
+                Ok(conn.intern_atom(false, name)?.reply()?.atom)
+            };
+            Some(InputAtoms {
+                matrix: atom(b"Coordinate Transformation Matrix")?,
+                float: atom(b"FLOAT")?,
+                device_node: atom(b"Device Node")?,
+            })
+        } else {
+            eprintln!("The X server does not support XInput2, touchscreens will not be mapped");
+            None
+        };
+
+        let backend = X11Backend {
+            conn,
+            root,
+            mm_per_pixel,
+            target_display: display.into(),
+            touch_filter,
+            input_atoms,
+            known_transform: KnownTransform::default(),
+        };
+        // Fail early when the display does not exist
+        backend.find_target()?;
+        Ok(backend)
+    }
+
+    fn find_target(&self) -> Result<Target, X11Error> {
+        let resources = self
+            .conn
+            .randr_get_screen_resources_current(self.root)?
+            .reply()?;
+        let config_timestamp = resources.config_timestamp;
+
+        let mut target = None;
+        for output in resources.outputs {
+            let info = self
+                .conn
+                .randr_get_output_info(output, config_timestamp)?
+                .reply()?;
+            if info.name == self.target_display.as_bytes() {
+                target = Some(info.crtc);
+                break;
+            }
+        }
+        let crtc = match target {
+            Some(0) => return Err(X11Error::OutputDisabled(self.target_display.clone())),
+            Some(crtc) => crtc,
+            None => return Err(X11Error::OutputNotFound(self.target_display.clone())),
+        };
+
+        let mut info = None;
+        let mut others = vec![];
+        for other in resources.crtcs {
+            let crtc_info = self
+                .conn
+                .randr_get_crtc_info(other, config_timestamp)?
+                .reply()?;
+            if other == crtc {
+                info = Some(crtc_info);
+            } else if crtc_info.mode != 0 {
+                others.push((other, crtc_info));
+            }
+        }
+
+        Ok(Target {
+            crtc,
+            info: info.ok_or_else(|| X11Error::OutputDisabled(self.target_display.clone()))?,
+            config_timestamp,
+            others,
+        })
+    }
+
+    /// Rotate the target CRTC. Like `xrandr`, CRTCs right of or below it move
+    /// along as its size changes, and the screen is grown before and shrunk
+    /// after reconfiguring so that every CRTC always fits.
+    fn rotate(&self, new_state: &Orientation) -> Result<(), X11Error> {
+        let target = self.find_target()?;
+        let info = &target.info;
+        let rotation = transform_to_rotation(new_state.wayland_state);
+        let sideways = |r: Rotation| u16::from(r) & 0b1010 != 0;
+
+        let new_size = if sideways(info.rotation) != sideways(rotation) {
+            (info.height as i32, info.width as i32)
+        } else {
+            (info.width as i32, info.height as i32)
+        };
+        let others: Vec<Rect> = target.others.iter().map(|(_, o)| rect(o)).collect();
+        let (positions, screen) = relayout(rect(info), new_size, &others);
+        let moved: Vec<_> = target
+            .others
+            .iter()
+            .zip(positions)
+            .filter(|((_, other), position)| *position != (other.x as i32, other.y as i32))
+            .map(|((crtc, other), (x, y))| (*crtc, other, x as i16, y as i16))
+            .collect();
+
+        let geometry = self.conn.get_geometry(self.root)?.reply()?;
+        let current = (geometry.width as i32, geometry.height as i32);
+
+        self.conn.grab_server()?;
+        let result = (|| {
+            // Grow first so that the rotated CRTC fits
+            let grown = (screen.0.max(current.0), screen.1.max(current.1));
+            if grown != current {
+                self.set_screen_size(grown)?;
+            }
+            for (crtc, other, x, y) in &moved {
+                self.set_crtc(
+                    *crtc,
+                    other,
+                    target.config_timestamp,
+                    *x,
+                    *y,
+                    other.rotation,
+                )?;
+            }
+            self.set_crtc(
+                target.crtc,
+                info,
+                target.config_timestamp,
+                info.x,
+                info.y,
+                rotation,
+            )?;
+            if screen != grown {
+                self.set_screen_size(screen)?;
+            }
+            Ok(())
+        })();
+        self.conn.ungrab_server()?;
+        self.conn.flush()?;
+        result
+    }
+
+    fn set_crtc(
+        &self,
+        crtc: Crtc,
+        info: &GetCrtcInfoReply,
+        config_timestamp: u32,
+        x: i16,
+        y: i16,
+        rotation: Rotation,
+    ) -> Result<(), X11Error> {
+        let reply = self
+            .conn
+            .randr_set_crtc_config(
+                crtc,
+                info.timestamp,
+                config_timestamp,
+                x,
+                y,
+                info.mode,
+                rotation,
+                &info.outputs,
+            )?
+            .reply()?;
+        if reply.status == randr::SetConfig::SUCCESS {
+            Ok(())
+        } else {
+            Err(X11Error::ConfigFailed(self.target_display.clone()))
+        }
+    }
+
+    fn set_screen_size(&self, (width, height): (i32, i32)) -> Result<(), X11Error> {
+        self.conn
+            .randr_set_screen_size(
+                self.root,
+                width as u16,
+                height as u16,
+                (width as f64 * self.mm_per_pixel.0).round() as u32,
+                (height as f64 * self.mm_per_pixel.1).round() as u32,
+            )?
+            .check()?;
+        Ok(())
+    }
+
+    /// Touch devices to map: those matching the include patterns when any
+    /// are set, otherwise every touchscreen and tablet. Excluded devices are
+    /// always skipped.
+    fn touch_devices(&self, atoms: &InputAtoms) -> Result<Vec<XIDeviceInfo>, X11Error> {
+        let devices = self
+            .conn
+            .xinput_xi_query_device(xinput::Device::ALL)?
+            .reply()?
+            .infos;
+
+        let mut selected = vec![];
+        for device in devices {
+            if device.type_ != DeviceType::SLAVE_POINTER
+                && device.type_ != DeviceType::FLOATING_SLAVE
+            {
+                continue;
+            }
+            let name = String::from_utf8_lossy(&device.name).into_owned();
+            if !self.touch_filter.matches(&[&name]) {
+                continue;
+            }
+            if self.touch_filter.has_include() || self.is_touch_device(&device, atoms)? {
+                selected.push(device);
+            }
+        }
+        Ok(selected)
+    }
+
+    /// Whether udev tags the device as touchscreen or tablet. Without udev
+    /// data, fall back to the XInput2 direct touch class, which pens lack.
+    fn is_touch_device(&self, device: &XIDeviceInfo, atoms: &InputAtoms) -> Result<bool, X11Error> {
+        if let Some(node) = self.device_node(device.deviceid, atoms.device_node)? {
+            let name = Path::new(&node).file_name().unwrap_or_default();
+            let properties = udev_properties(&Path::new("/sys/class/input").join(name));
+            if !properties.is_empty() {
+                let tagged = |key: &str| properties.get(key).map(|v| v == "1") == Some(true);
+                return Ok(tagged("ID_INPUT_TOUCHSCREEN") || tagged("ID_INPUT_TABLET"));
+            }
+        }
+        Ok(device.classes.iter().any(|class| {
+            matches!(&class.data, DeviceClassData::Touch(touch) if touch.mode == TouchMode::DIRECT)
+        }))
+    }
+
+    /// The `/dev/input` node set by the libinput or evdev driver.
+    fn device_node(&self, device: DeviceId, property: Atom) -> Result<Option<String>, X11Error> {
+        let reply = self
+            .conn
+            .xinput_xi_get_property(device, false, property, AtomEnum::ANY.into(), 0, 256)?
+            .reply()?;
+        match reply.items {
+            XIGetPropertyItems::Data8(bytes) if !bytes.is_empty() => {
+                let bytes = bytes.split(|b| *b == 0).next().unwrap_or_default();
+                Ok(Some(String::from_utf8_lossy(bytes).into_owned()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Set the `Coordinate Transformation Matrix` of all touch devices, fitting
+    /// the rotation to where the target output sits on the screen.
+    fn map_touchscreens(&self, rotation: [f32; 9]) -> Result<(), X11Error> {
+        let atoms = match &self.input_atoms {
+            Some(atoms) => atoms,
+            None => return Ok(()),
+        };
+        let info = self.find_target()?.info;
+        let screen = self.conn.get_geometry(self.root)?.reply()?;
+        let matrix = fit_to_output(
+            rotation,
+            (
+                info.x as i32,
+                info.y as i32,
+                info.width as i32,
+                info.height as i32,
+            ),
+            (screen.width as i32, screen.height as i32),
+        );
+        let data = XIChangePropertyAux::Data32(matrix.iter().map(|v| v.to_bits()).collect());
+
+        for device in self.touch_devices(atoms)? {
+            self.conn
+                .xinput_xi_change_property(
+                    device.deviceid,
+                    PropMode::REPLACE,
+                    atoms.matrix,
+                    atoms.float,
+                    9,
+                    &data,
+                )?
+                .check()?;
+        }
+        Ok(())
+    }
+}
+
+impl DisplayManager for X11Backend {
+    fn change_rotation_state(&mut self, new_state: &Orientation) -> Result<(), String> {
+        self.rotate(new_state).map_err(|e| e.to_string())?;
+        self.known_transform.set(new_state.wayland_state);
+
+        // Support Touchscreen and Styli on some 2-in-1 devices
+        let mut matrix = [0.; 9];
+        for (value, raw) in matrix.iter_mut().zip(new_state.matrix) {
+            *value = raw.parse().unwrap();
+        }
+        self.map_touchscreens(matrix)
+            .map_err(|e| format!("Unable to map touchscreens: {}", e))
+    }
+
+    fn get_rotation_state(&mut self) -> Result<Transform, String> {
+        let target = self.find_target().map_err(|e| e.to_string())?;
+        let transform = rotation_to_transform(target.info.rotation);
+        Ok(self.known_transform.set(transform))
+    }
+
+    fn poll(&mut self) -> Result<Option<Transform>, String> {
+        let mut screen_changed = false;
+        while let Some(event) = self.conn.poll_for_event().map_err(|e| e.to_string())? {
+            screen_changed |= matches!(event, Event::RandrScreenChangeNotify(_));
+        }
+        if !screen_changed {
+            return Ok(None);
+        }
+
+        let target = self.find_target().map_err(|e| e.to_string())?;
+        let transform = rotation_to_transform(target.info.rotation);
+        Ok(self.known_transform.changed(transform))
+    }
+}
+
+/// Position and size of a CRTC on the screen.
+type Rect = (i32, i32, i32, i32);
+
+fn rect(info: &GetCrtcInfoReply) -> Rect {
+    (
+        info.x as i32,
+        info.y as i32,
+        info.width as i32,
+        info.height as i32,
+    )
+}
+
+/// Lay out the other CRTCs for the target taking `new_size`: those right of
+/// or below it move along as its size changes. Returns their new positions
+/// and the screen size that fits them all.
+fn relayout(target: Rect, new_size: (i32, i32), others: &[Rect]) -> (Vec<(i32, i32)>, (i32, i32)) {
+    let (target_x, target_y, width, height) = target;
+    let (dx, dy) = (new_size.0 - width, new_size.1 - height);
+    let mut screen = (target_x + new_size.0, target_y + new_size.1);
+    let positions = others
+        .iter()
+        .map(|&(x, y, other_width, other_height)| {
+            let x = if x >= target_x + width { x + dx } else { x };
+            let y = if y >= target_y + height { y + dy } else { y };
+            screen.0 = screen.0.max(x + other_width);
+            screen.1 = screen.1.max(y + other_height);
+            (x, y)
+        })
+        .collect();
+    (positions, screen)
+}
+
+/// RandR rotates counter-clockwise, like `xrandr --rotate left` for 90°.
+fn transform_to_rotation(transform: Transform) -> Rotation {
+    match transform {
+        Transform::_90 => Rotation::ROTATE90,
+        Transform::_180 => Rotation::ROTATE180,
+        Transform::_270 => Rotation::ROTATE270,
+        _ => Rotation::ROTATE0,
+    }
+}
+
+fn rotation_to_transform(rotation: Rotation) -> Transform {
+    // Ignore reflections
+    match u16::from(rotation) & 0b1111 {
+        0b0010 => Transform::_90,
+        0b0100 => Transform::_180,
+        0b1000 => Transform::_270,
+        _ => Transform::Normal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+
+    #[test]
+    fn transforms_map_to_rotations() {
+        for (transform, rotation) in [
+            (Transform::Normal, Rotation::ROTATE0),
+            (Transform::_90, Rotation::ROTATE90),
+            (Transform::_180, Rotation::ROTATE180),
+            (Transform::_270, Rotation::ROTATE270),
+        ] {
+            assert_eq!(transform_to_rotation(transform), rotation);
+            assert_eq!(rotation_to_transform(rotation), transform);
+        }
+        assert_eq!(
+            rotation_to_transform(Rotation::ROTATE90 | Rotation::REFLECT_X),
+            Transform::_90
+        );
+    }
+
+    #[test]
+    fn crtcs_after_the_target_move_along() {
+        // A 1920x1080 panel turned upright, with a monitor to its right and
+        // one below
+        let (positions, screen) = relayout(
+            (0, 0, 1920, 1080),
+            (1080, 1920),
+            &[(1920, 0, 2560, 1440), (0, 1080, 1280, 1024)],
+        );
+        assert_eq!(positions, [(1080, 0), (0, 1920)]);
+        assert_eq!(screen, (3640, 2944));
+
+        // A monitor to the left stays put
+        let (positions, screen) =
+            relayout((2560, 0, 1920, 1080), (1080, 1920), &[(0, 0, 2560, 1440)]);
+        assert_eq!(positions, [(0, 0)]);
+        assert_eq!(screen, (3640, 1920));
+    }
+
+    /// A private X server, if Xvfb is installed.
+    struct Xvfb {
+        server: Child,
+        display: String,
+    }
+
+    impl Xvfb {
+        fn start() -> Option<Xvfb> {
+            let mut server = Command::new("Xvfb")
+                .args([
+                    "-displayfd",
+                    "1",
+                    "-screen",
+                    "0",
+                    "1024x768x24",
+                    "-nolisten",
+                    "tcp",
+                ])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            // Printed once the server accepts connections
+            let mut number = String::new();
+            BufReader::new(server.stdout.take()?)
+                .read_line(&mut number)
+                .ok()?;
+            Some(Xvfb {
+                server,
+                display: format!(":{}", number.trim()),
+            })
+        }
+    }
+
+    impl Drop for Xvfb {
+        fn drop(&mut self) {
+            let _ = self.server.kill();
+            let _ = self.server.wait();
+        }
+    }
+
+    /// The first enabled output and whether its CRTC can be rotated.
+    fn first_output(display: &str) -> Option<(String, bool)> {
+        let (conn, screen_num) = x11rb::connect(Some(display)).ok()?;
+        let root = conn.setup().roots[screen_num].root;
+        let resources = conn
+            .randr_get_screen_resources_current(root)
+            .ok()?
+            .reply()
+            .ok()?;
+        for output in resources.outputs {
+            let info = conn
+                .randr_get_output_info(output, resources.config_timestamp)
+                .ok()?
+                .reply()
+                .ok()?;
+            if info.crtc == 0 {
+                continue;
+            }
+            let crtc = conn
+                .randr_get_crtc_info(info.crtc, resources.config_timestamp)
+                .ok()?
+                .reply()
+                .ok()?;
+            return Some((
+                String::from_utf8_lossy(&info.name).into_owned(),
+                crtc.rotations.contains(Rotation::ROTATE90),
+            ));
+        }
+        None
+    }
+
+    fn orientation(transform: Transform) -> &'static Orientation {
+        crate::ORIENTATIONS
+            .iter()
+            .find(|o| o.wayland_state == transform)
+            .unwrap()
+    }
+
+    #[test]
+    fn rotates_output_on_xvfb() {
+        let xvfb = match Xvfb::start() {
+            Some(xvfb) => xvfb,
+            None => {
+                eprintln!("Xvfb not found, skipping");
+                return;
+            }
+        };
+        let output = match first_output(&xvfb.display) {
+            Some((output, true)) => output,
+            _ => {
+                eprintln!("Xvfb has no rotatable output, skipping");
+                return;
+            }
+        };
+        let filter = InputFilter::new(&[], &[]).unwrap();
+        let mut backend = X11Backend::connect_to(Some(&xvfb.display), &output, filter).unwrap();
+        let screen_size = |backend: &X11Backend| {
+            let geometry = backend
+                .conn
+                .get_geometry(backend.root)
+                .unwrap()
+                .reply()
+                .unwrap();
+            (geometry.width, geometry.height)
+        };
+
+        assert_eq!(backend.get_rotation_state(), Ok(Transform::Normal));
+        assert_eq!(screen_size(&backend), (1024, 768));
+
+        backend
+            .change_rotation_state(orientation(Transform::_90))
+            .unwrap();
+        assert_eq!(backend.get_rotation_state(), Ok(Transform::_90));
+        assert_eq!(screen_size(&backend), (768, 1024));
+        // Our own rotation is not reported as an external one
+        assert_eq!(backend.poll(), Ok(None));
+
+        backend
+            .change_rotation_state(orientation(Transform::Normal))
+            .unwrap();
+        assert_eq!(backend.get_rotation_state(), Ok(Transform::Normal));
+        assert_eq!(screen_size(&backend), (1024, 768));
+    }
+}
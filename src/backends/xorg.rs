@@ -2,81 +2,208 @@ use std::process::Command;
 
 use wayland_client::protocol::wl_output::Transform;
 
-use super::DisplayManager;
+use super::{DisplayManager, InputFilter};
+use crate::matrix::fit_to_output;
 use crate::Orientation;
 
 pub struct XorgBackend {
-    touchscreens: Vec<String>,
+    touch_filter: InputFilter,
     target_display: String,
 }
 
+/// Touchscreen mapped when none is configured, as `xinput` does not tell
+/// touchscreens apart from other inputs.
+pub const DEFAULT_TOUCHSCREEN: &str = "ELAN0732:00 04F3:22E1";
+
 impl XorgBackend {
-    pub fn new(display: &str, touchscreens: Vec<String>) -> Self {
+    pub fn new(display: &str, touch_filter: InputFilter) -> Self {
         XorgBackend {
             target_display: display.into(),
-            touchscreens,
+            touch_filter,
+        }
+    }
+
+    /// Names of the inputs selected by the touch filter, as listed by
+    /// `xinput`.
+    fn touchscreens(&self) -> Result<Vec<String>, String> {
+        let output = Command::new("xinput")
+            .args(["list", "--name-only"])
+            .output()
+            .map_err(|e| format!("Unable to run xinput: {}", e))?;
+        let mut touchscreens: Vec<String> = vec![];
+        for name in String::from_utf8_lossy(&output.stdout).lines() {
+            if (self.touch_filter.has_include() || name == DEFAULT_TOUCHSCREEN)
+                && self.touch_filter.matches(&[name])
+                && !touchscreens.iter().any(|t| t == name)
+            {
+                touchscreens.push(name.to_string());
+            }
+        }
+        Ok(touchscreens)
+    }
+}
+
+/// A connected output as listed by `xrandr`.
+struct XrandrOutput {
+    name: String,
+    /// Width, height and position, when the output is enabled.
+    geometry: Option<(i32, i32, i32, i32)>,
+    rotation: Transform,
+}
+
+/// The screen and its outputs as listed by `xrandr`.
+struct XrandrLayout {
+    screen: Option<(i32, i32)>,
+    outputs: Vec<XrandrOutput>,
+}
+
+impl XorgBackend {
+    fn query_outputs() -> Result<XrandrLayout, String> {
+        let output = Command::new("xrandr")
+            .output()
+            .map_err(|e| format!("Unable to run xrandr: {}", e))?;
+        let raw = String::from_utf8_lossy(&output.stdout);
+        let pattern = regex::Regex::new(
+            r"^(\S+) connected (?:primary )?(?:(\d+)x(\d+)\+(\d+)\+(\d+) )?(?:(normal|left|inverted|right) )?\(",
+        )
+        .unwrap();
+        let screen = regex::Regex::new(r"current (\d+) x (\d+)")
+            .unwrap()
+            .captures(&raw)
+            .and_then(|c| Some((c[1].parse().ok()?, c[2].parse().ok()?)));
+
+        let outputs = raw
+            .lines()
+            .filter_map(|line| pattern.captures(line))
+            .map(|captures| {
+                let number = |i: usize| captures.get(i).and_then(|m| m.as_str().parse().ok());
+                XrandrOutput {
+                    name: captures[1].to_string(),
+                    geometry: match (number(2), number(3), number(4), number(5)) {
+                        (Some(w), Some(h), Some(x), Some(y)) => Some((w, h, x, y)),
+                        _ => None,
+                    },
+                    rotation: match captures.get(6).map(|m| m.as_str()) {
+                        Some("inverted") => Transform::_180,
+                        Some("right") => Transform::_270,
+                        Some("left") => Transform::_90,
+                        _ => Transform::Normal,
+                    },
+                }
+            })
+            .collect();
+        Ok(XrandrLayout { screen, outputs })
+    }
+
+    /// Rotate the target output with a single xrandr call. Outputs right of
+    /// or below it move along as its width or height changes, so that the
+    /// layout keeps no gaps or overlaps.
+    fn rotate_output(&self, new_state: &Orientation) -> Result<(), String> {
+        let outputs = XorgBackend::query_outputs()?.outputs;
+        let target = outputs
+            .iter()
+            .find(|o| o.name == self.target_display)
+            .ok_or_else(|| format!("Display {} not found in xrandr output", self.target_display))?;
+
+        let mut args = vec![
+            "--output".to_string(),
+            target.name.clone(),
+            "--rotate".to_string(),
+            new_state.x_state.to_string(),
+        ];
+        if let Some((width, height, x, y)) = target.geometry {
+            let sideways = |t: Transform| t == Transform::_90 || t == Transform::_270;
+            let (new_width, new_height) =
+                if sideways(target.rotation) != sideways(new_state.wayland_state) {
+                    (height, width)
+                } else {
+                    (width, height)
+                };
+            let (dx, dy) = (new_width - width, new_height - height);
+
+            for other in outputs.iter().filter(|o| o.name != target.name) {
+                if let Some((_, _, ox, oy)) = other.geometry {
+                    let nx = if ox >= x + width { ox + dx } else { ox };
+                    let ny = if oy >= y + height { oy + dy } else { oy };
+                    if (nx, ny) != (ox, oy) {
+                        args.extend([
+                            "--output".to_string(),
+                            other.name.clone(),
+                            "--pos".to_string(),
+                            format!("{}x{}", nx, ny),
+                        ]);
+                    }
+                }
+            }
+        }
+
+        let status = Command::new("xrandr")
+            .args(&args)
+            .status()
+            .map_err(|e| format!("Unable to run xrandr: {}", e))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("xrandr {} failed: {}", args.join(" "), status))
+        }
+    }
+
+    /// The rotation matrix fitted to where the target output now sits on the
+    /// screen, or left as is when xrandr does not tell.
+    fn touch_matrix(&self, new_state: &Orientation) -> [f32; 9] {
+        let mut rotation = [0.; 9];
+        for (value, raw) in rotation.iter_mut().zip(new_state.matrix) {
+            *value = raw.parse().unwrap();
+        }
+        match XorgBackend::query_outputs() {
+            Ok(XrandrLayout {
+                screen: Some(screen),
+                outputs,
+            }) => outputs
+                .iter()
+                .find(|o| o.name == self.target_display)
+                .and_then(|o| o.geometry)
+                .map(|(width, height, x, y)| fit_to_output(rotation, (x, y, width, height), screen))
+                .unwrap_or(rotation),
+            _ => rotation,
         }
     }
 }
 
 impl DisplayManager for XorgBackend {
-    fn change_rotation_state(&mut self, new_state: &Orientation) {
-        Command::new("xrandr")
-            .arg("-o")
-            .arg(new_state.x_state)
-            .spawn()
-            .expect("Xrandr rotate command failed to start")
-            .wait()
-            .expect("Xrandr rotate command wait failed");
+    fn change_rotation_state(&mut self, new_state: &Orientation) -> Result<(), String> {
+        self.rotate_output(new_state)
+            .map_err(|e| format!("Unable to rotate {}: {}", self.target_display, e))?;
 
         // Support Touchscreen and Styli on some 2-in-1 devices
-        for touchscreen in &self.touchscreens {
+        let matrix: Vec<String> = self
+            .touch_matrix(new_state)
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        for touchscreen in self.touchscreens()? {
             Command::new("xinput")
                 .arg("set-prop")
-                .arg(touchscreen)
+                .arg(&touchscreen)
                 .arg("Coordinate Transformation Matrix")
-                .args(new_state.matrix)
-                .spawn()
-                .expect("Xinput rotate command failed to start")
-                .wait()
-                .expect("Xinput rotate command wait failed");
+                .args(&matrix)
+                .status()
+                .map_err(|e| format!("Unable to run xinput: {}", e))?;
         }
+        Ok(())
     }
 
     fn get_rotation_state(&mut self) -> Result<Transform, String> {
-        let raw_rotation_state = String::from_utf8(
-            Command::new("xrandr")
-                .output()
-                .expect("Xrandr get outputs command failed to start")
-                .stdout,
-        )
-        .unwrap();
-        let xrandr_output_pattern = regex::Regex::new(format!(
-                r"^{} connected .+? .*? (normal |inverted |left |right )?\(normal left inverted right x axis y axis\) .+$",
-                regex::escape(&self.target_display),
-            ).as_str()).unwrap();
-        for xrandr_output_line in raw_rotation_state.split('\n') {
-            if !xrandr_output_pattern.is_match(xrandr_output_line) {
-                continue;
-            }
-
-            let xrandr_output_captures =
-                xrandr_output_pattern.captures(xrandr_output_line).unwrap();
-            if let Some(transform) = xrandr_output_captures.get(1) {
-                return Ok(match transform.as_str() {
-                    "inverted" => Transform::_180,
-                    "right" => Transform::_270,
-                    "left" => Transform::_90,
-                    _ => Transform::Normal,
-                });
-            } else {
-                return Ok(Transform::Normal);
-            }
-        }
-
-        Err(format!(
-            "Unable to determine rotation state: display {} not found in xrandr output",
-            self.target_display
-        ))
+        XorgBackend::query_outputs()?
+            .outputs
+            .iter()
+            .find(|o| o.name == self.target_display)
+            .map(|o| o.rotation)
+            .ok_or_else(|| {
+                format!(
+                    "Unable to determine rotation state: display {} not found in xrandr output",
+                    self.target_display
+                )
+            })
     }
 }
@@ -0,0 +1,855 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::{ArgMatches, ValueSource};
+use serde::Deserialize;
+use wayland_client::protocol::wl_output::Transform;
+
+use crate::matrix::Matrix3;
+use crate::{transform_to_env, DeviceState};
+
+const AXIS_PAIRS: [&str; 6] = ["xy", "yx", "zy", "yz", "xz", "zx"];
+
+/// Conditions a `disable-inputs` rule can apply in, besides the orientations
+/// as passed to hooks.
+const RULE_CONDITIONS: [&str; 7] = [
+    "always",
+    "tablet-mode",
+    "rotated",
+    "laptop",
+    "tent",
+    "stand",
+    "tablet",
+];
+const RULE_ORIENTATIONS: [&str; 4] = ["normal", "90", "inverted", "270"];
+
+/// Where accelerometer readings come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SensorKind {
+    /// Poll the IIO sysfs attributes directly.
+    Iio,
+    /// Read the IIO character device in buffered mode.
+    IioBuffer,
+    /// Ask iio-sensor-proxy over D-Bus.
+    SensorProxy,
+}
+
+impl FromStr for SensorKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "iio" => Ok(SensorKind::Iio),
+            "iio-buffer" => Ok(SensorKind::IioBuffer),
+            "sensor-proxy" => Ok(SensorKind::SensorProxy),
+            _ => Err(format!("Unknown sensor '{}'", s)),
+        }
+    }
+}
+
+/// How to handle the display being rotated by someone else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExternalRotation {
+    /// Keep the new rotation until the device is turned to match it.
+    Lock,
+    /// Rotate back to the orientation of the sensor.
+    Reassert,
+}
+
+impl FromStr for ExternalRotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lock" => Ok(ExternalRotation::Lock),
+            "reassert" => Ok(ExternalRotation::Reassert),
+            _ => Err(format!("Unknown external rotation policy '{}'", s)),
+        }
+    }
+}
+
+/// Inputs to disable while any of the conditions hold.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DisableRule {
+    /// `tablet-mode`, `rotated`, `always`, a posture or an orientation.
+    #[serde(default = "DisableRule::default_when")]
+    pub when: Vec<String>,
+    /// Sway input types, or `kbd_backlight` for the keyboard backlight.
+    #[serde(default)]
+    pub types: Vec<String>,
+    /// Globs matched against sway input identifiers.
+    #[serde(default)]
+    pub identifiers: Vec<String>,
+}
+
+impl DisableRule {
+    fn default_when() -> Vec<String> {
+        vec!["tablet-mode".to_string()]
+    }
+
+    /// A rule disabling inputs of the given types in tablet mode.
+    pub fn in_tablet_mode(types: &[&str]) -> Self {
+        DisableRule {
+            when: DisableRule::default_when(),
+            types: types.iter().map(|t| t.to_string()).collect(),
+            identifiers: vec![],
+        }
+    }
+
+    pub fn applies(&self, state: &DeviceState) -> bool {
+        self.when.iter().any(|condition| match condition.as_str() {
+            "always" => true,
+            "tablet-mode" => state.tablet_mode,
+            "rotated" => state.orientation != Transform::Normal,
+            posture if RULE_CONDITIONS.contains(&posture) => {
+                state.posture.is_some_and(|p| p.as_str() == posture)
+            }
+            orientation => transform_to_env(&state.orientation) == orientation,
+        })
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for condition in &self.when {
+            if !RULE_CONDITIONS.contains(&condition.as_str())
+                && !RULE_ORIENTATIONS.contains(&condition.as_str())
+            {
+                return Err(format!(
+                    "Invalid condition '{}' in 'disable-inputs', expected one of {:?} or {:?}",
+                    condition, RULE_CONDITIONS, RULE_ORIENTATIONS
+                ));
+            }
+        }
+        for identifier in &self.identifiers {
+            glob::Pattern::new(identifier)
+                .map_err(|e| format!("Invalid identifier '{}': {}", identifier, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Fully resolved runtime configuration.
+///
+/// Values are layered: built-in defaults, then the top level of the config
+/// file, then the selected profile and finally explicit command line flags.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub sleep: u64,
+    pub sensor: SensorKind,
+    pub external_rotation: ExternalRotation,
+    pub serve_sensor_proxy: bool,
+    pub device: String,
+    /// Accelerometer in the base for posture detection: detected when unset,
+    /// disabled when `none`.
+    pub base_device: Option<String>,
+    /// IIO hinge angle sensor: detected when unset, disabled when `none`.
+    pub hinge_device: Option<String>,
+    /// Hinge angle in degrees below which the screen stays in normal orientation.
+    pub min_hinge_angle: Option<f32>,
+    /// Only rotate while in tablet mode.
+    pub tablet_mode_only: bool,
+    pub display: String,
+    /// Touch and pen inputs to map; all on sway when empty.
+    pub touchscreens: Vec<String>,
+    pub touchscreen_exclude: Vec<String>,
+    pub threshold: f32,
+    pub invert_x: bool,
+    pub invert_y: bool,
+    pub invert_z: bool,
+    pub invert_xy: String,
+    /// Explicit axis mapping, taking precedence over the invert options given
+    /// at the same level. Invert options on the command line or in a profile
+    /// replace a matrix from the level below.
+    pub axis_matrix: Option<Matrix3>,
+    pub normalization_factor: Option<f32>,
+    pub disable_keyboard: bool,
+    pub disable_touchpad: bool,
+    pub disable_inputs: Vec<DisableRule>,
+    /// Inputs never disabled, as identifier or name globs.
+    pub keep_inputs: Vec<String>,
+    /// Keyboards whose absence means tablet mode, as `vendor:product` or name.
+    pub detachable_keyboards: Vec<String>,
+    pub hooks: Vec<String>,
+    pub beforehooks: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            sleep: 500,
+            sensor: SensorKind::Iio,
+            external_rotation: ExternalRotation::Reassert,
+            serve_sensor_proxy: false,
+            device: "/sys/bus/iio/devices/iio:device*".into(),
+            base_device: None,
+            hinge_device: None,
+            min_hinge_angle: None,
+            tablet_mode_only: false,
+            display: "eDP-1".into(),
+            touchscreens: vec![],
+            touchscreen_exclude: vec![],
+            threshold: 0.5,
+            invert_x: false,
+            invert_y: false,
+            invert_z: false,
+            invert_xy: "xy".into(),
+            axis_matrix: None,
+            normalization_factor: None,
+            disable_keyboard: false,
+            disable_touchpad: false,
+            disable_inputs: vec![],
+            keep_inputs: vec![],
+            detachable_keyboards: vec![],
+            hooks: vec![],
+            beforehooks: vec![],
+        }
+    }
+}
+
+/// A set of optional settings, as found at the top level of the config file
+/// or inside a profile.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct Options {
+    sleep: Option<u64>,
+    sensor: Option<SensorKind>,
+    external_rotation: Option<ExternalRotation>,
+    serve_sensor_proxy: Option<bool>,
+    device: Option<String>,
+    base_device: Option<String>,
+    hinge_device: Option<String>,
+    min_hinge_angle: Option<f32>,
+    tablet_mode_only: Option<bool>,
+    display: Option<String>,
+    #[serde(alias = "touchscreen")]
+    touchscreens: Option<Vec<String>>,
+    touchscreen_exclude: Option<Vec<String>>,
+    threshold: Option<f32>,
+    invert_x: Option<bool>,
+    invert_y: Option<bool>,
+    invert_z: Option<bool>,
+    invert_xy: Option<String>,
+    axis_matrix: Option<Matrix3>,
+    normalization_factor: Option<f32>,
+    disable_keyboard: Option<bool>,
+    disable_touchpad: Option<bool>,
+    disable_inputs: Option<Vec<DisableRule>>,
+    #[serde(alias = "keep-input")]
+    keep_inputs: Option<Vec<String>>,
+    #[serde(alias = "detachable-keyboard")]
+    detachable_keyboards: Option<Vec<String>>,
+    hooks: Option<Vec<String>>,
+    beforehooks: Option<Vec<String>>,
+    /// Keys not listed above, rejected after parsing since
+    /// `deny_unknown_fields` does not work with `flatten`.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct Profile {
+    /// Glob matched against the DMI product name to select this profile automatically.
+    #[serde(rename = "match")]
+    product_match: Option<String>,
+    #[serde(flatten)]
+    options: Options,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct ConfigFile {
+    /// Name of the profile to use when none is given on the command line.
+    profile: Option<String>,
+    profiles: BTreeMap<String, Profile>,
+    #[serde(flatten)]
+    options: Options,
+}
+
+impl Options {
+    fn check_keys(&self, location: &str) -> Result<(), String> {
+        match self.unknown.keys().next() {
+            Some(key) => Err(format!("unknown key '{}'{}", key, location)),
+            None => Ok(()),
+        }
+    }
+
+    fn apply(self, config: &mut Config) {
+        let inverts = self.invert_x.is_some()
+            || self.invert_y.is_some()
+            || self.invert_z.is_some()
+            || self.invert_xy.is_some();
+        if let Some(v) = self.sleep {
+            config.sleep = v;
+        }
+        if let Some(v) = self.sensor {
+            config.sensor = v;
+        }
+        if let Some(v) = self.external_rotation {
+            config.external_rotation = v;
+        }
+        if let Some(v) = self.serve_sensor_proxy {
+            config.serve_sensor_proxy = v;
+        }
+        if let Some(v) = self.device {
+            config.device = v;
+        }
+        if let Some(v) = self.base_device {
+            config.base_device = Some(v);
+        }
+        if let Some(v) = self.hinge_device {
+            config.hinge_device = Some(v);
+        }
+        if let Some(v) = self.min_hinge_angle {
+            config.min_hinge_angle = Some(v);
+        }
+        if let Some(v) = self.tablet_mode_only {
+            config.tablet_mode_only = v;
+        }
+        if let Some(v) = self.display {
+            config.display = v;
+        }
+        if let Some(v) = self.touchscreens {
+            config.touchscreens = v;
+        }
+        if let Some(v) = self.touchscreen_exclude {
+            config.touchscreen_exclude = v;
+        }
+        if let Some(v) = self.threshold {
+            config.threshold = v;
+        }
+        if let Some(v) = self.invert_x {
+            config.invert_x = v;
+        }
+        if let Some(v) = self.invert_y {
+            config.invert_y = v;
+        }
+        if let Some(v) = self.invert_z {
+            config.invert_z = v;
+        }
+        if let Some(v) = self.invert_xy {
+            config.invert_xy = v;
+        }
+        if let Some(v) = self.axis_matrix {
+            config.axis_matrix = Some(v);
+        } else if inverts {
+            // Invert options replace an axis matrix from an outer level
+            config.axis_matrix = None;
+        }
+        if let Some(v) = self.normalization_factor {
+            config.normalization_factor = Some(v);
+        }
+        if let Some(v) = self.disable_keyboard {
+            config.disable_keyboard = v;
+        }
+        if let Some(v) = self.disable_touchpad {
+            config.disable_touchpad = v;
+        }
+        if let Some(v) = self.disable_inputs {
+            config.disable_inputs = v;
+        }
+        if let Some(v) = self.keep_inputs {
+            config.keep_inputs = v;
+        }
+        if let Some(v) = self.detachable_keyboards {
+            config.detachable_keyboards = v;
+        }
+        if let Some(v) = self.hooks {
+            config.hooks = v;
+        }
+        if let Some(v) = self.beforehooks {
+            config.beforehooks = v;
+        }
+    }
+}
+
+impl Config {
+    /// Build the configuration from the config file (if any) and the command line.
+    pub fn load(matches: &ArgMatches) -> Result<Config, String> {
+        let mut config = Config::default();
+
+        let file = match matches.value_of("config") {
+            Some(path) => Some(Config::read_file(Path::new(path))?),
+            None => match Config::path(matches) {
+                Some(path) if path.exists() => Some(Config::read_file(&path)?),
+                _ => None,
+            },
+        };
+
+        if let Some(mut file) = file {
+            let profile_name = matches
+                .value_of("profile")
+                .map(String::from)
+                .or_else(|| file.profile.take());
+            let profile = match profile_name {
+                Some(name) => Some(
+                    file.profiles
+                        .remove(&name)
+                        .ok_or(format!("Profile '{}' not found in config file", name))?,
+                ),
+                None => detect_profile(&mut file.profiles),
+            };
+
+            file.options.apply(&mut config);
+            if let Some(profile) = profile {
+                profile.options.apply(&mut config);
+            }
+        } else if let Some(name) = matches.value_of("profile") {
+            return Err(format!(
+                "Profile '{}' requested but no config file was found",
+                name
+            ));
+        }
+
+        config.apply_matches(matches)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Path of the config file in use, whether it exists yet or not.
+    pub fn path(matches: &ArgMatches) -> Option<PathBuf> {
+        matches
+            .value_of("config")
+            .map(PathBuf::from)
+            .or_else(default_config_path)
+    }
+
+    fn read_file(path: &Path) -> Result<ConfigFile, String> {
+        let raw = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read config file {}: {}", path.display(), e))?;
+        let file: ConfigFile = toml::from_str(&raw)
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+        file.options
+            .check_keys("")
+            .and_then(|_| {
+                file.profiles.iter().try_for_each(|(name, profile)| {
+                    profile
+                        .options
+                        .check_keys(&format!(" in profile '{}'", name))
+                })
+            })
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+        Ok(file)
+    }
+
+    /// Override values with flags that were explicitly passed on the command line.
+    fn apply_matches(&mut self, matches: &ArgMatches) -> Result<(), String> {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if from_cli("sleep") {
+            self.sleep = parse_arg(matches, "sleep")?;
+        }
+        if from_cli("sensor") {
+            self.sensor = parse_arg(matches, "sensor")?;
+        }
+        if from_cli("external-rotation") {
+            self.external_rotation = parse_arg(matches, "external-rotation")?;
+        }
+        if from_cli("device") {
+            self.device = matches.value_of("device").unwrap().into();
+        }
+        if from_cli("base-device") {
+            self.base_device = matches.value_of("base-device").map(String::from);
+        }
+        if from_cli("hinge-device") {
+            self.hinge_device = matches.value_of("hinge-device").map(String::from);
+        }
+        if from_cli("min-hinge-angle") {
+            self.min_hinge_angle = Some(parse_arg(matches, "min-hinge-angle")?);
+        }
+        if from_cli("display") {
+            self.display = matches.value_of("display").unwrap().into();
+        }
+        if from_cli("touchscreen") {
+            self.touchscreens = matches.get_many("touchscreen").unwrap().cloned().collect();
+        }
+        if from_cli("touchscreen-exclude") {
+            self.touchscreen_exclude = matches
+                .get_many("touchscreen-exclude")
+                .unwrap()
+                .cloned()
+                .collect();
+        }
+        if from_cli("threshold") {
+            self.threshold = parse_arg(matches, "threshold")?;
+        }
+        if from_cli("invert-xy") {
+            self.invert_xy = matches.value_of("invert-xy").unwrap().into();
+        }
+        let inverts = [
+            "invert-x",
+            "invert-y",
+            "invert-z",
+            "no-invert-x",
+            "no-invert-y",
+            "no-invert-z",
+            "invert-xy",
+        ];
+        if inverts.iter().any(|id| from_cli(id)) {
+            self.axis_matrix = None;
+        }
+        if from_cli("axis-matrix") {
+            self.axis_matrix = Some(parse_arg(matches, "axis-matrix")?);
+        }
+        if from_cli("normalization-factor") {
+            self.normalization_factor = Some(parse_arg(matches, "normalization-factor")?);
+        }
+        if from_cli("hooks") {
+            self.hooks = matches
+                .values_of("hooks")
+                .unwrap()
+                .map(String::from)
+                .collect();
+        }
+        if from_cli("keep-input") {
+            self.keep_inputs = matches
+                .values_of("keep-input")
+                .unwrap()
+                .map(String::from)
+                .collect();
+        }
+        if from_cli("detachable-keyboard") {
+            self.detachable_keyboards = matches
+                .values_of("detachable-keyboard")
+                .unwrap()
+                .map(String::from)
+                .collect();
+        }
+        if from_cli("beforehooks") {
+            self.beforehooks = matches
+                .values_of("beforehooks")
+                .unwrap()
+                .map(String::from)
+                .collect();
+        }
+        // Flags turn options on and their `no-` counterparts turn them off,
+        // whichever was given last
+        let flag = |value: &mut bool, id: &str| {
+            if matches.is_present(id) {
+                *value = true;
+            } else if matches.is_present(format!("no-{}", id)) {
+                *value = false;
+            }
+        };
+        flag(&mut self.invert_x, "invert-x");
+        flag(&mut self.invert_y, "invert-y");
+        flag(&mut self.invert_z, "invert-z");
+        flag(&mut self.disable_keyboard, "keyboard");
+        flag(&mut self.disable_touchpad, "touchpad");
+        flag(&mut self.tablet_mode_only, "tablet-mode-only");
+        flag(&mut self.serve_sensor_proxy, "serve-sensor-proxy");
+
+        Ok(())
+    }
+
+    /// The matrix mapping device axes onto the screen's x, y and z axes.
+    ///
+    /// Without an explicit `axis-matrix`, `invert-xy` picks the source of the
+    /// screen x and y axes (the remaining one becomes z) and the `invert-*`
+    /// options flip the device axes.
+    pub fn axis_matrix(&self) -> Matrix3 {
+        if let Some(matrix) = self.axis_matrix {
+            return matrix;
+        }
+
+        let unit = |axis: char| match axis {
+            'x' => [1., 0., 0.],
+            'y' => [0., 1., 0.],
+            _ => [0., 0., 1.],
+        };
+        let mut xy = self.invert_xy.chars();
+        let x_source = xy.next().unwrap_or('x');
+        let y_source = xy.next().unwrap_or('y');
+        let z_source = ['x', 'y', 'z']
+            .iter()
+            .copied()
+            .find(|a| *a != x_source && *a != y_source)
+            .unwrap_or('z');
+
+        let mut rows = [unit(x_source), unit(y_source), unit(z_source)];
+        for (axis, invert) in [self.invert_x, self.invert_y, self.invert_z]
+            .iter()
+            .enumerate()
+        {
+            if *invert {
+                for row in rows.iter_mut() {
+                    row[axis] = -row[axis];
+                }
+            }
+        }
+        Matrix3(rows)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !AXIS_PAIRS.contains(&self.invert_xy.as_str()) {
+            return Err(format!(
+                "Invalid value '{}' for 'invert-xy', expected one of {:?}",
+                self.invert_xy, AXIS_PAIRS
+            ));
+        }
+        if self.serve_sensor_proxy && self.sensor == SensorKind::SensorProxy {
+            return Err(
+                "Cannot serve net.hadess.SensorProxy while reading from it as the sensor"
+                    .to_string(),
+            );
+        }
+        for rule in &self.disable_inputs {
+            rule.validate()?;
+        }
+        Ok(())
+    }
+
+    /// All `disable-inputs` rules, including those implied by the
+    /// `disable-keyboard` and `disable-touchpad` flags.
+    pub fn disable_rules(&self) -> Vec<DisableRule> {
+        let mut rules = self.disable_inputs.clone();
+        if self.disable_keyboard {
+            rules.push(DisableRule::in_tablet_mode(&["keyboard"]));
+        }
+        if self.disable_touchpad {
+            rules.push(DisableRule::in_tablet_mode(&["touchpad"]));
+        }
+        rules
+    }
+}
+
+fn parse_arg<T: std::str::FromStr>(matches: &ArgMatches, id: &str) -> Result<T, String> {
+    matches
+        .value_of(id)
+        .unwrap()
+        .parse::<T>()
+        .map_err(|_| format!("The argument '{}' has an invalid value", id))
+}
+
+/// `$XDG_CONFIG_HOME/rot8/config.toml`, falling back to `~/.config/rot8/config.toml`.
+fn default_config_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(base.join("rot8").join("config.toml"))
+}
+
+/// Pick the first profile whose `match` glob matches the machine's DMI product name.
+fn detect_profile(profiles: &mut BTreeMap<String, Profile>) -> Option<Profile> {
+    let product = fs::read_to_string("/sys/class/dmi/id/product_name").ok()?;
+    let product = product.trim();
+    let name = profiles
+        .iter()
+        .find(|(_, profile)| {
+            profile
+                .product_match
+                .as_deref()
+                .and_then(|p| glob::Pattern::new(p).ok())
+                .is_some_and(|p| p.matches(product))
+        })
+        .map(|(name, _)| name.clone())?;
+    println!("Using config profile '{}' for '{}'", name, product);
+    profiles.remove(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Load a config from a temporary file with the given contents and the
+    /// given command line flags.
+    fn load(file: &str, args: &[&str]) -> Result<Config, String> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "rot8-config-{}-{}.toml",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::write(&path, file).unwrap();
+        let mut argv = vec!["rot8", "--config", path.to_str().unwrap()];
+        argv.extend(args);
+        let matches = crate::cli().try_get_matches_from(argv).unwrap();
+        let config = Config::load(&matches);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn rule_conditions() {
+        use crate::posture::Posture;
+        let rule = |when: &[&str]| DisableRule {
+            when: when.iter().map(|w| w.to_string()).collect(),
+            ..DisableRule::in_tablet_mode(&["keyboard"])
+        };
+        let state = |orientation, posture, tablet_mode| DeviceState {
+            orientation,
+            posture,
+            tablet_mode,
+        };
+        let laptop = state(Transform::Normal, Some(Posture::Laptop), false);
+        let tent = state(Transform::_180, Some(Posture::Tent), true);
+
+        assert!(!DisableRule::in_tablet_mode(&["keyboard"]).applies(&laptop));
+        assert!(DisableRule::in_tablet_mode(&["keyboard"]).applies(&tent));
+        assert!(rule(&["always"]).applies(&laptop));
+        assert!(rule(&["rotated"]).applies(&tent));
+        assert!(!rule(&["rotated"]).applies(&laptop));
+        assert!(rule(&["laptop", "stand"]).applies(&laptop));
+        assert!(!rule(&["stand"]).applies(&tent));
+        assert!(rule(&["inverted"]).applies(&tent));
+        assert!(!rule(&["90"]).applies(&tent));
+        assert!(!rule(&[]).applies(&tent));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let error = load("threshhold = 0.9\n", &[]).unwrap_err();
+        assert!(error.contains("unknown key 'threshhold'"), "{}", error);
+        let error = load("[profiles.yoga]\ninvert-q = true\n", &[]).unwrap_err();
+        assert!(
+            error.contains("unknown key 'invert-q' in profile 'yoga'"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn accepts_flag_names_for_list_keys() {
+        let config = load(
+            "touchscreen = [\"ELAN*\"]\nkeep-input = [\"*Logitech*\"]\ndetachable-keyboard = [\"045e:09c0\"]\n",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(config.touchscreens, ["ELAN*"]);
+        assert_eq!(config.keep_inputs, ["*Logitech*"]);
+        assert_eq!(config.detachable_keyboards, ["045e:09c0"]);
+    }
+
+    #[test]
+    fn file_overrides_defaults() {
+        let config = load("sleep = 100\ndisplay = \"DSI-1\"\n", &[]).unwrap();
+        assert_eq!(config.sleep, 100);
+        assert_eq!(config.display, "DSI-1");
+        assert_eq!(config.threshold, Config::default().threshold);
+    }
+
+    #[test]
+    fn profile_overrides_file() {
+        let file = "sleep = 100\nprofile = \"a\"\n\
+                    [profiles.a]\nsleep = 200\n\
+                    [profiles.b]\nsleep = 300\ninvert-x = true\n";
+        assert_eq!(load(file, &[]).unwrap().sleep, 200);
+
+        let config = load(file, &["--profile", "b"]).unwrap();
+        assert_eq!(config.sleep, 300);
+        assert!(config.invert_x);
+
+        assert!(load(file, &["--profile", "c"]).is_err());
+    }
+
+    #[test]
+    fn command_line_overrides_file() {
+        let file = "sleep = 100\ndisplay = \"DSI-1\"\n[profiles.a]\nthreshold = 0.7\n";
+        let config = load(file, &["--sleep", "50", "--profile", "a", "-t", "0.3"]).unwrap();
+        assert_eq!(config.sleep, 50);
+        assert_eq!(config.threshold, 0.3);
+        // Defaults of flags that were not given do not override the file
+        assert_eq!(config.display, "DSI-1");
+    }
+
+    #[test]
+    fn command_line_turns_off_file_flags() {
+        let file = "invert-x = true\ndisable-keyboard = true\ntablet-mode-only = true\n";
+        let config = load(file, &[]).unwrap();
+        assert!(config.invert_x && config.disable_keyboard && config.tablet_mode_only);
+
+        let config = load(
+            file,
+            &[
+                "--no-invert-x",
+                "--no-disable-keyboard",
+                "--no-tablet-mode-only",
+            ],
+        )
+        .unwrap();
+        assert!(!config.invert_x && !config.disable_keyboard && !config.tablet_mode_only);
+
+        // The last of a flag and its negation wins
+        assert!(load("", &["--no-invert-y", "--invert-y"]).unwrap().invert_y);
+        assert!(!load("", &["--invert-y", "--no-invert-y"]).unwrap().invert_y);
+    }
+
+    #[test]
+    fn invert_presets() {
+        let matrix = |config: Config| config.axis_matrix().0;
+        assert_eq!(matrix(Config::default()), Matrix3::IDENTITY.0);
+        assert_eq!(
+            matrix(Config {
+                invert_xy: "yx".into(),
+                ..Config::default()
+            }),
+            [[0., 1., 0.], [1., 0., 0.], [0., 0., 1.]]
+        );
+        assert_eq!(
+            matrix(Config {
+                invert_xy: "zy".into(),
+                ..Config::default()
+            }),
+            [[0., 0., 1.], [0., 1., 0.], [1., 0., 0.]]
+        );
+        assert_eq!(
+            matrix(Config {
+                invert_z: true,
+                ..Config::default()
+            }),
+            [[1., 0., 0.], [0., 1., 0.], [0., 0., -1.]]
+        );
+        // The example from the README
+        assert_eq!(
+            matrix(Config {
+                invert_x: true,
+                invert_xy: "yx".into(),
+                ..Config::default()
+            }),
+            [[0., 1., 0.], [-1., 0., 0.], [0., 0., 1.]]
+        );
+    }
+
+    #[test]
+    fn invert_options_replace_file_axis_matrix() {
+        let file = "axis-matrix = \"0, 1, 0; 1, 0, 0; 0, 0, -1\"\n\
+                    [profiles.a]\ninvert-y = true\n";
+        let explicit = Matrix3([[0., 1., 0.], [1., 0., 0.], [0., 0., -1.]]);
+        let config = load(file, &[]).unwrap();
+        assert_eq!(config.axis_matrix(), explicit);
+
+        let config = load(file, &["--invert-x"]).unwrap();
+        assert_eq!(config.axis_matrix, None);
+        assert_eq!(
+            config.axis_matrix().0,
+            [[-1., 0., 0.], [0., 1., 0.], [0., 0., 1.]]
+        );
+
+        let config = load(file, &["--invert-xy", "yx"]).unwrap();
+        assert_eq!(
+            config.axis_matrix().0,
+            [[0., 1., 0.], [1., 0., 0.], [0., 0., 1.]]
+        );
+
+        let config = load(file, &["--profile", "a"]).unwrap();
+        assert_eq!(
+            config.axis_matrix().0,
+            [[1., 0., 0.], [0., -1., 0.], [0., 0., 1.]]
+        );
+
+        // A matrix on the command line still wins over inverts from the file
+        let config = load(
+            "invert-x = true\n",
+            &["--axis-matrix", "0, 1, 0; 1, 0, 0; 0, 0, -1"],
+        )
+        .unwrap();
+        assert_eq!(config.axis_matrix(), explicit);
+    }
+}
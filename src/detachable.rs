@@ -0,0 +1,224 @@
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+
+use glob::Pattern;
+
+use crate::sensors::iio::read_attr;
+
+/// Tracks whether the keyboard of a detachable, like a Surface type cover,
+/// is attached. Input devices are rescanned whenever the kernel reports an
+/// input device being added or removed.
+pub struct DetachableKeyboard {
+    matchers: Vec<Matcher>,
+    /// Kernel uevent socket, `None` if hotplug events are unavailable so
+    /// every check rescans.
+    uevents: Option<OwnedFd>,
+    changed: bool,
+    attached: bool,
+}
+
+enum Matcher {
+    /// Hexadecimal `vendor:product` id.
+    Id(String, String),
+    /// Glob matched against the device name.
+    Name(Pattern),
+}
+
+impl Matcher {
+    fn parse(value: &str) -> Result<Matcher, String> {
+        if let Some((vendor, product)) = value.split_once(':') {
+            let is_hex = |s: &str| s.len() == 4 && s.chars().all(|c| c.is_ascii_hexdigit());
+            if is_hex(vendor) && is_hex(product) {
+                return Ok(Matcher::Id(
+                    vendor.to_ascii_lowercase(),
+                    product.to_ascii_lowercase(),
+                ));
+            }
+        }
+        Pattern::new(value)
+            .map(Matcher::Name)
+            .map_err(|e| format!("Invalid detachable keyboard '{}': {}", value, e))
+    }
+
+    fn matches(&self, input: &Path) -> bool {
+        match self {
+            Matcher::Id(vendor, product) => {
+                read_attr(&input.join("id/vendor")).is_ok_and(|v| v == *vendor)
+                    && read_attr(&input.join("id/product")).is_ok_and(|p| p == *product)
+            }
+            Matcher::Name(pattern) => {
+                read_attr(&input.join("name")).is_ok_and(|name| pattern.matches(&name))
+            }
+        }
+    }
+}
+
+impl DetachableKeyboard {
+    /// Watch for any of the given keyboards, each either a `vendor:product`
+    /// id like `045e:09c0` or a device name (glob).
+    pub fn new(keyboards: &[String]) -> Result<Self, String> {
+        let matchers = keyboards
+            .iter()
+            .map(|k| Matcher::parse(k))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let uevents = open_uevent_socket()
+            .map_err(|e| {
+                eprintln!(
+                    "Unable to listen for hotplug events: {}, checking for the keyboard on every reading",
+                    e
+                )
+            })
+            .ok();
+
+        Ok(DetachableKeyboard {
+            matchers,
+            uevents,
+            changed: true,
+            attached: false,
+        })
+    }
+
+    /// Whether one of the keyboards is currently present.
+    pub fn is_attached(&mut self) -> bool {
+        self.read_uevents();
+        if mem::take(&mut self.changed) || self.uevents.is_none() {
+            let attached = self.scan();
+            if attached != self.attached {
+                println!(
+                    "Detachable keyboard {}",
+                    if attached { "attached" } else { "detached" }
+                );
+            }
+            self.attached = attached;
+        }
+        self.attached
+    }
+
+    /// Drain pending uevents, flagging a rescan for input hotplug.
+    fn read_uevents(&mut self) {
+        let fd = match &self.uevents {
+            Some(fd) => fd.as_raw_fd(),
+            None => return,
+        };
+        let mut buffer = [0u8; 8192];
+        loop {
+            let len = unsafe {
+                libc::recv(
+                    fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            if len > 0 {
+                self.changed |= is_input_hotplug(&buffer[..len as usize]);
+                continue;
+            }
+            let error = io::Error::last_os_error();
+            match error.raw_os_error() {
+                _ if len == 0 => return,
+                Some(libc::EAGAIN) => return,
+                Some(libc::EINTR) => {}
+                // Events were lost, one of them may have been ours
+                Some(libc::ENOBUFS) => self.changed = true,
+                _ => {
+                    eprintln!(
+                        "Unable to read hotplug events: {}, checking for the keyboard on every reading",
+                        error
+                    );
+                    self.uevents = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn scan(&self) -> bool {
+        let inputs = match fs::read_dir("/sys/class/input") {
+            Ok(inputs) => inputs,
+            Err(_) => return false,
+        };
+        inputs
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("input"))
+            .any(|entry| self.matchers.iter().any(|m| m.matches(&entry.path())))
+    }
+}
+
+/// Open a netlink socket receiving the kernel's uevents.
+fn open_uevent_socket() -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            libc::NETLINK_KOBJECT_UEVENT,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    // The kernel's multicast group, as opposed to udev's
+    addr.nl_groups = 1;
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+/// Whether a kernel uevent, a header like `add@/devices/...` followed by
+/// NUL separated `KEY=value` fields, is an input device coming or going.
+fn is_input_hotplug(message: &[u8]) -> bool {
+    let mut action = None;
+    let mut subsystem = None;
+    for field in message.split(|&b| b == 0).skip(1) {
+        if let Some(value) = field.strip_prefix(b"ACTION=") {
+            action = Some(value);
+        } else if let Some(value) = field.strip_prefix(b"SUBSYSTEM=") {
+            subsystem = Some(value);
+        }
+    }
+    subsystem == Some(b"input") && matches!(action, Some(b"add") | Some(b"remove"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_input_hotplug() {
+        assert!(is_input_hotplug(
+            b"add@/devices/virtual/input/input42\0ACTION=add\0DEVPATH=/devices/virtual/input/input42\0SUBSYSTEM=input\0NAME=\"Type Cover\"\0SEQNUM=1234\0"
+        ));
+        assert!(is_input_hotplug(
+            b"remove@/devices/virtual/input/input42\0ACTION=remove\0DEVPATH=/devices/virtual/input/input42\0SUBSYSTEM=input\0SEQNUM=1235\0"
+        ));
+    }
+
+    #[test]
+    fn ignores_other_uevents() {
+        assert!(!is_input_hotplug(
+            b"change@/devices/virtual/input/input42\0ACTION=change\0DEVPATH=/devices/virtual/input/input42\0SUBSYSTEM=input\0"
+        ));
+        assert!(!is_input_hotplug(
+            b"add@/devices/pci0000:00/0000:00:14.0/usb1/1-1\0ACTION=add\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-1\0SUBSYSTEM=usb\0"
+        ));
+        // Only the header mentions an input device
+        assert!(!is_input_hotplug(b"add@/devices/virtual/input/input42\0"));
+        assert!(!is_input_hotplug(b""));
+    }
+}
@@ -2,39 +2,444 @@ extern crate clap;
 extern crate glob;
 extern crate regex;
 
-use std::fs;
 use std::path::Path;
 use std::process::Command;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use clap::{App, Arg};
-use glob::glob;
 use wayland_client::protocol::wl_output::Transform;
 
 mod backends;
-use backends::{sway::SwayBackend, wlroots::WaylandBackend, xorg::XorgBackend, DisplayManager};
+mod config;
+mod detachable;
+mod matrix;
+mod posture;
+mod reload;
+mod sensors;
+mod service;
+mod tablet_mode;
+use backends::{
+    compile_patterns, sway::SwayBackend, wlroots::WaylandBackend, x11::X11Backend,
+    xorg::XorgBackend, DisplayManager, InputFilter,
+};
+use config::{Config, ExternalRotation, SensorKind};
+use detachable::DetachableKeyboard;
+use matrix::Matrix3;
+use posture::{Posture, PostureDetector};
+use reload::ConfigWatcher;
+use sensors::{
+    hinge::find_hinge,
+    iio::{find_accelerometer, find_base_accelerometer, IioPollingSource},
+    iio_buffer::IioBufferSource,
+    sensor_proxy::SensorProxySource,
+    AccelerometerSource, Sample, STANDARD_GRAVITY,
+};
+use service::SensorProxyService;
+use tablet_mode::{tablet_mode, TabletModeSwitch};
 
 const ROT8_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// How often to look for a tablet mode switch while none is present, as its
+/// driver may be loaded after rot8 started.
+const SWITCH_SEARCH_INTERVAL: Duration = Duration::from_secs(10);
 
 pub struct Orientation {
     vector: (f32, f32),
     wayland_state: Transform,
     x_state: &'static str,
+    sensor_proxy_state: &'static str,
     matrix: [&'static str; 9],
 }
 
+static ORIENTATIONS: [Orientation; 4] = [
+    Orientation {
+        vector: (0.0, -1.0),
+        wayland_state: Transform::Normal,
+        x_state: "normal",
+        sensor_proxy_state: "normal",
+        matrix: ["1", "0", "0", "0", "1", "0", "0", "0", "1"],
+    },
+    Orientation {
+        vector: (0.0, 1.0),
+        wayland_state: Transform::_180,
+        x_state: "inverted",
+        sensor_proxy_state: "bottom-up",
+        matrix: ["-1", "0", "1", "0", "-1", "1", "0", "0", "1"],
+    },
+    Orientation {
+        vector: (-1.0, 0.0),
+        wayland_state: Transform::_270,
+        x_state: "right",
+        sensor_proxy_state: "right-up",
+        matrix: ["0", "1", "0", "-1", "0", "1", "0", "0", "1"],
+    },
+    Orientation {
+        vector: (1.0, 0.0),
+        wayland_state: Transform::_90,
+        x_state: "left",
+        sensor_proxy_state: "left-up",
+        matrix: ["0", "-1", "1", "1", "0", "0", "0", "0", "1"],
+    },
+];
+
+/// What is known about how the device is being used, passed to backends to
+/// decide which inputs should be active.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeviceState {
+    pub orientation: Transform,
+    pub posture: Option<Posture>,
+    /// Whether the keyboard is out of reach, e.g. folded behind the screen.
+    pub tablet_mode: bool,
+}
+
 fn main() -> Result<(), String> {
-    let mut path_x: String = "".to_string();
-    let mut path_y: String = "".to_string();
-    let mut path_z: String = "".to_string();
+    let matches = cli().get_matches();
+
+    if matches.is_present("version") {
+        println!("{}", ROT8_VERSION);
+        return Ok(());
+    }
+
+    let mut config = Config::load(&matches)?;
+    let mut axes = source_axes(&config);
+    let oneshot = matches.is_present("oneshot");
+
+    let mut backend = build_backend(&config)?;
+    let (mut source, mut posture_detector) = build_sensors(&config)?;
+    let mut service = build_service(&config)?;
+    let mut tablet_switch = TabletModeSwitch::find();
+    let mut last_switch_search = Instant::now();
+    let mut detachable = build_detachable(&config)?;
+    if config.tablet_mode_only
+        && tablet_switch.is_none()
+        && detachable.is_none()
+        && posture_detector.is_none()
+    {
+        eprintln!("No tablet mode switch found, rotation stays disabled with --tablet-mode-only");
+    }
+    let mut watcher = ConfigWatcher::new(Config::path(&matches));
+
+    let mut old_state = backend.get_rotation_state()?;
+    let mut old_posture: Option<Posture> = None;
+    let mut old_keyboard_attached: Option<bool> = None;
+    let mut old_device_state: Option<DeviceState> = None;
+    let mut current_orient: &Orientation = &ORIENTATIONS[0];
+    let mut rotation_lock: Option<Transform> = None;
+
+    loop {
+        if watcher.reload_requested() {
+            match Config::load(&matches) {
+                Ok(new_config) if new_config != config => match rebuild(&config, &new_config) {
+                    Ok(rebuilt) => {
+                        if sensors_changed(&config, &new_config) {
+                            // The kernel lets only one reader open an IIO
+                            // buffer, so the old sensors have to go first
+                            drop(posture_detector);
+                            drop(source);
+                            match build_sensors(&new_config) {
+                                Ok(sensors) => (source, posture_detector) = sensors,
+                                Err(e) => {
+                                    eprintln!("Ignoring new configuration: {}", e);
+                                    (source, posture_detector) = build_sensors(&config)?;
+                                    continue;
+                                }
+                            }
+                        }
+                        if let Some(mut new_backend) = rebuilt.backend {
+                            old_state = new_backend.get_rotation_state().unwrap_or(old_state);
+                            old_device_state = None;
+                            backend = new_backend;
+                        }
+                        if let Some(new_detachable) = rebuilt.detachable {
+                            detachable = new_detachable;
+                        }
+                        if let Some(new_service) = rebuilt.service {
+                            service = new_service;
+                        }
+                        config = new_config;
+                        axes = source_axes(&config);
+                        println!("Configuration reloaded");
+                    }
+                    Err(e) => eprintln!("Ignoring new configuration: {}", e),
+                },
+                Ok(_) => {}
+                Err(e) => eprintln!("Ignoring new configuration: {}", e),
+            }
+        }
+
+        let sample = match source.read_sample() {
+            Ok(sample) => sample,
+            Err(e) if oneshot => return Err(e),
+            Err(e) => {
+                eprintln!("Failed to read accelerometer: {}", e);
+                thread::sleep(Duration::from_millis(config.sleep));
+                continue;
+            }
+        };
+        let [x, y, z] = screen_vector(&sample, &config, &axes);
+
+        match backend.poll() {
+            Ok(Some(transform)) => {
+                println!(
+                    "Display rotated externally to {}",
+                    transform_to_env(&transform)
+                );
+                old_state = transform;
+                if config.external_rotation == ExternalRotation::Lock {
+                    rotation_lock = Some(transform);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("{}", e),
+        }
+
+        if tablet_switch.is_none() && last_switch_search.elapsed() >= SWITCH_SEARCH_INTERVAL {
+            tablet_switch = TabletModeSwitch::find();
+            last_switch_search = Instant::now();
+        }
+
+        let posture = match &mut posture_detector {
+            Some(detector) => detector.update([x, y, z]).unwrap_or_else(|e| {
+                eprintln!("Failed to read base accelerometer: {}", e);
+                old_posture
+            }),
+            None => None,
+        };
+        let hinge_angle = posture_detector.as_ref().and_then(|d| d.hinge_angle());
+        let keyboard_attached = detachable.as_mut().map(|k| k.is_attached());
+        // A tablet mode switch is authoritative, a detached keyboard comes next
+        let tablet_switch_state = match &mut tablet_switch {
+            Some(switch) => match switch.is_active() {
+                Ok(active) => Some(active),
+                Err(e) => {
+                    // Most likely removed, search for it again
+                    eprintln!("{}", e);
+                    tablet_switch = None;
+                    None
+                }
+            },
+            None => None,
+        }
+        .or(keyboard_attached.map(|attached| !attached));
+
+        if config.tablet_mode_only
+            && !tablet_switch_state.unwrap_or_else(|| posture.is_some_and(|p| p.is_tablet_like()))
+        {
+            current_orient = &ORIENTATIONS[0];
+        } else if posture == Some(Posture::Tent) {
+            // The hinge is on top, whatever the lid sensor makes of it
+            current_orient = &ORIENTATIONS[1];
+        } else if hinge_angle
+            .zip(config.min_hinge_angle)
+            .is_some_and(|(angle, min)| angle < min)
+        {
+            current_orient = &ORIENTATIONS[0];
+        } else if let Some(orient) = detect_orientation(x, y, config.threshold) {
+            current_orient = orient;
+        }
+
+        // Hold an external rotation until the device is turned to match it
+        let mut target_orient = current_orient;
+        if let Some(lock) = rotation_lock {
+            if current_orient.wayland_state == lock {
+                println!("Rotation lock released");
+                rotation_lock = None;
+            } else if let Some(locked) = ORIENTATIONS.iter().find(|o| o.wayland_state == lock) {
+                target_orient = locked;
+            }
+        }
+
+        if let Some(service) = &mut service {
+            if let Err(e) = service.set_orientation(target_orient.sensor_proxy_state) {
+                eprintln!("{}", e);
+            }
+        }
+
+        let rotated = rotation_lock.is_none() && target_orient.wayland_state != old_state;
+        if rotated || posture != old_posture || keyboard_attached != old_keyboard_attached {
+            let hinge_angle_env = hinge_angle.map_or(String::new(), |a| format!("{:.0}", a));
+            let env = [
+                (
+                    "ORIENTATION",
+                    transform_to_env(&target_orient.wayland_state),
+                ),
+                ("PREV_ORIENTATION", transform_to_env(&old_state)),
+                ("POSTURE", posture.map_or("", |p| p.as_str())),
+                ("PREV_POSTURE", old_posture.map_or("", |p| p.as_str())),
+                ("HINGE_ANGLE", &hinge_angle_env),
+                (
+                    "KEYBOARD_ATTACHED",
+                    keyboard_attached.map_or("", |a| if a { "1" } else { "0" }),
+                ),
+            ];
+            run_hooks(&config.beforehooks, &env);
+
+            // Left at the old state on failure, so that it is tried again
+            let applied = rotated
+                && backend
+                    .change_rotation_state(target_orient)
+                    .map_err(|e| eprintln!("{}", e))
+                    .is_ok();
+
+            run_hooks(&config.hooks, &env);
+
+            if applied {
+                println!(
+                    "Rotated to {}, {} ms after the reading",
+                    transform_to_env(&target_orient.wayland_state),
+                    sample.timestamp.elapsed().as_millis()
+                );
+                old_state = target_orient.wayland_state;
+            }
+
+            old_posture = posture;
+            old_keyboard_attached = keyboard_attached;
+        }
 
+        let device_state = DeviceState {
+            orientation: old_state,
+            posture,
+            tablet_mode: tablet_mode(tablet_switch_state, posture, old_state),
+        };
+        if old_device_state != Some(device_state) {
+            if let Err(e) = backend.update_device_state(&device_state) {
+                eprintln!("{}", e);
+            }
+            old_device_state = Some(device_state);
+        }
+
+        if oneshot {
+            return Ok(());
+        }
+    }
+}
+
+/// Whether the accelerometer source and posture detector need rebuilding.
+/// They cannot be built next to the old ones, so this happens only when
+/// their settings change.
+fn sensors_changed(config: &Config, new_config: &Config) -> bool {
+    new_config.sensor != config.sensor
+        || new_config.device != config.device
+        || new_config.sleep != config.sleep
+        || new_config.base_device != config.base_device
+        || new_config.hinge_device != config.hinge_device
+        || source_axes(new_config) != source_axes(config)
+}
+
+fn run_hooks(hooks: &[String], env: &[(&str, &str)]) {
+    for hook in hooks.iter() {
+        Command::new("bash")
+            .arg("-c")
+            .arg(hook)
+            .envs(env.iter().copied())
+            .spawn()
+            .expect("A hook failed to start.")
+            .wait()
+            .expect("Waiting for a hook failed.");
+    }
+}
+
+/// Everything rebuilt from a changed configuration.
+struct Rebuilt {
+    backend: Option<Box<dyn DisplayManager>>,
+    detachable: Option<Option<DetachableKeyboard>>,
+    service: Option<Option<SensorProxyService>>,
+}
+
+/// Build everything the new configuration needs up front, so that a broken
+/// configuration leaves the old one active. Parts whose settings did not
+/// change are kept. The sensors are left to the caller, see
+/// `sensors_changed`.
+fn rebuild(config: &Config, new_config: &Config) -> Result<Rebuilt, String> {
+    let backend = if new_config.display != config.display
+        || new_config.touchscreens != config.touchscreens
+        || new_config.touchscreen_exclude != config.touchscreen_exclude
+        || new_config.disable_rules() != config.disable_rules()
+        || new_config.keep_inputs != config.keep_inputs
+    {
+        Some(build_backend(new_config)?)
+    } else {
+        None
+    };
+    let service = if new_config.serve_sensor_proxy != config.serve_sensor_proxy {
+        Some(build_service(new_config)?)
+    } else {
+        None
+    };
+    let detachable = if new_config.detachable_keyboards != config.detachable_keyboards {
+        Some(build_detachable(new_config)?)
+    } else {
+        None
+    };
+    Ok(Rebuilt {
+        backend,
+        detachable,
+        service,
+    })
+}
+
+/// The matrix taking samples from the configured source onto the screen
+/// axes. iio-sensor-proxy reports orientations with its own quirks applied.
+fn source_axes(config: &Config) -> Matrix3 {
+    if config.sensor == SensorKind::SensorProxy {
+        Matrix3::IDENTITY
+    } else {
+        config.axis_matrix()
+    }
+}
+
+/// Map a sample onto the normalized screen axes.
+fn screen_vector(sample: &Sample, config: &Config, axes: &Matrix3) -> [f32; 3] {
+    // Normalize vectors. Readings in m/s² are relative to gravity, raw counts
+    // are scaled by their own magnitude unless a factor was given.
+    let norm_factor = config
+        .normalization_factor
+        .or(if sample.physical {
+            Some(STANDARD_GRAVITY)
+        } else {
+            None
+        })
+        .unwrap_or_else(|| {
+            f32::sqrt(sample.x * sample.x + sample.y * sample.y + sample.z * sample.z)
+        });
+
+    axes.apply([
+        sample.x / norm_factor,
+        sample.y / norm_factor,
+        sample.z / norm_factor,
+    ])
+}
+
+/// The first orientation whose gravity vector is closer than the threshold
+/// to the screen vector. None while the device lies flat or is tilted
+/// between two orientations.
+fn detect_orientation(x: f32, y: f32, threshold: f32) -> Option<&'static Orientation> {
+    ORIENTATIONS.iter().find(|orient| {
+        let d = (x - orient.vector.0).powf(2.0) + (y - orient.vector.1).powf(2.0);
+        d < threshold
+    })
+}
+
+/// The command line interface, also used by the config tests.
+fn cli() -> App<'static> {
     let args = vec![
         Arg::with_name("oneshot")
             .long("oneshot")
             .short('O')
             .help("Instead of running continuously, just check the accelerometer and perform screen rotation if necessary once")
             .takes_value(false),
+        Arg::with_name("config")
+            .long("config")
+            .short('c')
+            .value_name("CONFIG")
+            .help("Read settings from this TOML file instead of $XDG_CONFIG_HOME/rot8/config.toml. Command line flags take precedence. The file is reloaded on change or SIGHUP.")
+            .takes_value(true),
+        Arg::with_name("profile")
+            .long("profile")
+            .short('p')
+            .value_name("PROFILE")
+            .help("Use the named profile from the config file instead of detecting it from the DMI product name")
+            .takes_value(true),
         Arg::with_name("sleep")
             .default_value("500")
             .long("sleep")
@@ -42,11 +447,58 @@ fn main() -> Result<(), String> {
             .value_name("SLEEP")
             .help("Set sleep millis")
             .takes_value(true),
+        Arg::with_name("sensor")
+            .default_value("iio")
+            .long("sensor")
+            .value_name("SENSOR")
+            .help("Poll the accelerometer through sysfs (iio), read it in IIO buffered mode (iio-buffer) or ask iio-sensor-proxy over D-Bus (sensor-proxy)")
+            .possible_values(["iio", "iio-buffer", "sensor-proxy"])
+            .takes_value(true),
+        Arg::with_name("external-rotation")
+            .default_value("reassert")
+            .long("external-rotation")
+            .value_name("POLICY")
+            .help("What to do when the display is rotated by someone else: keep it until the device is turned to match (lock) or rotate back to the sensor orientation (reassert)")
+            .possible_values(["lock", "reassert"])
+            .takes_value(true),
+        Arg::with_name("serve-sensor-proxy")
+            .long("serve-sensor-proxy")
+            .help("Publish the computed orientation on the system bus as net.hadess.SensorProxy, replacing iio-sensor-proxy")
+            .takes_value(false),
+        Arg::with_name("no-serve-sensor-proxy")
+            .long("no-serve-sensor-proxy")
+            .help("Turn off serve-sensor-proxy from the config file")
+            .overrides_with("serve-sensor-proxy")
+            .takes_value(false),
         Arg::with_name("device")
             .default_value("/sys/bus/iio/devices/iio:device*")
             .long("device")
             .value_name("DEVICE")
-            .help("Set accelerometer device")
+            .help("Set accelerometer device, either a sysfs path (glob) or the device's name or label. The accelerometer in the display is preferred when several match.")
+            .takes_value(true),
+        Arg::with_name("base-device")
+            .long("base-device")
+            .value_name("BASE_DEVICE")
+            .help("Set the accelerometer in the keyboard base used to detect the posture of convertibles, as a path or name. Detected automatically; 'none' disables posture detection.")
+            .takes_value(true),
+        Arg::with_name("hinge-device")
+            .long("hinge-device")
+            .value_name("HINGE_DEVICE")
+            .help("Set the IIO hinge angle sensor, as a path or name. Detected automatically; 'none' disables it.")
+            .takes_value(true),
+        Arg::with_name("tablet-mode-only")
+            .long("tablet-mode-only")
+            .help("Only rotate while in tablet mode, as reported by the SW_TABLET_MODE switch, and return to normal when it ends")
+            .takes_value(false),
+        Arg::with_name("no-tablet-mode-only")
+            .long("no-tablet-mode-only")
+            .help("Turn off tablet-mode-only from the config file")
+            .overrides_with("tablet-mode-only")
+            .takes_value(false),
+        Arg::with_name("min-hinge-angle")
+            .long("min-hinge-angle")
+            .value_name("DEGREES")
+            .help("Keep the normal orientation while the hinge is opened less than this many degrees, e.g. 200 to only rotate once folded past flat.")
             .takes_value(true),
         Arg::with_name("display")
             .default_value("eDP-1")
@@ -56,13 +508,32 @@ fn main() -> Result<(), String> {
             .help("Set Display Device")
             .takes_value(true),
         Arg::with_name("touchscreen")
-            .default_value("ELAN0732:00 04F3:22E1")
             .long("touchscreen")
             .short('i')
             .value_name("TOUCHSCREEN")
-            .help("Set Touchscreen input Device (X11 only)")
+            .help("Set Touchscreen input Device. Globs matched against the name (or sway identifier) of touch and pen inputs, all of which are mapped by default. The xrandr fallback on X11 defaults to 'ELAN0732:00 04F3:22E1'.")
+            .min_values(1)
+            .takes_value(true),
+        Arg::with_name("touchscreen-exclude")
+            .long("touchscreen-exclude")
+            .value_name("TOUCHSCREEN")
+            .help("Do not map touch and pen inputs matching these globs to the display")
             .min_values(1)
             .takes_value(true),
+        Arg::with_name("keep-input")
+            .long("keep-input")
+            .value_name("INPUT")
+            .help("Never disable inputs whose identifier or name matches these globs, e.g. external keyboards (Sway only). Comma-seperated.")
+            .takes_value(true)
+            .use_value_delimiter(true)
+            .require_value_delimiter(true),
+        Arg::with_name("detachable-keyboard")
+            .long("detachable-keyboard")
+            .value_name("KEYBOARD")
+            .help("Treat the device as a tablet while none of these keyboards are attached, given as vendor:product ids or names. Comma-seperated.")
+            .takes_value(true)
+            .use_value_delimiter(true)
+            .require_value_delimiter(true),
         Arg::with_name("threshold")
             .default_value("0.5")
             .long("threshold")
@@ -85,6 +556,21 @@ fn main() -> Result<(), String> {
             .short('Z')
             .help("Invert readings from the HW z axis")
             .takes_value(false),
+        Arg::with_name("no-invert-x")
+            .long("no-invert-x")
+            .help("Turn off invert-x from the config file")
+            .overrides_with("invert-x")
+            .takes_value(false),
+        Arg::with_name("no-invert-y")
+            .long("no-invert-y")
+            .help("Turn off invert-y from the config file")
+            .overrides_with("invert-y")
+            .takes_value(false),
+        Arg::with_name("no-invert-z")
+            .long("no-invert-z")
+            .help("Turn off invert-z from the config file")
+            .overrides_with("invert-z")
+            .takes_value(false),
         Arg::with_name("invert-xy")
             .default_value("xy")
             .long("invert-xy")
@@ -92,17 +578,38 @@ fn main() -> Result<(), String> {
             .help("Map hardware accelerometer axes to internal x and y respectively")
             .possible_values(["xy", "yx", "zy", "yz", "xz", "zx"])
             .takes_value(true),
+        Arg::with_name("axis-matrix")
+            .long("axis-matrix")
+            .short('m')
+            .value_name("MATRIX")
+            .help("Map hardware accelerometer axes to internal x, y and z with a 3x3 matrix given as rows, e.g. \"0, 1, 0; 1, 0, 0; 0, 0, -1\". Replaces the invert options.")
+            .conflicts_with_all(&["invert-x", "invert-y", "invert-z", "invert-xy"])
+            .takes_value(true),
         Arg::with_name("normalization-factor")
             .long("normalization-factor")
             .short('n')
             .value_name("NORMALIZATION_FACTOR")
-            .help("Set factor for sensor value normalization manually. By default this factor is calculated dynamically using the sensor's data.")
+            .help("Set factor for sensor value normalization manually. By default readings are compared to standard gravity when the sensor reports its scale, or normalized by their own magnitude otherwise.")
             .takes_value(true),
         Arg::with_name("keyboard")
             .long("disable-keyboard")
             .short('k')
             .help("Disable keyboard for tablet modes (Sway only)")
             .takes_value(false),
+        Arg::with_name("touchpad")
+            .long("disable-touchpad")
+            .help("Disable touchpad for tablet modes (Sway only)")
+            .takes_value(false),
+        Arg::with_name("no-keyboard")
+            .long("no-disable-keyboard")
+            .help("Turn off disable-keyboard from the config file")
+            .overrides_with("keyboard")
+            .takes_value(false),
+        Arg::with_name("no-touchpad")
+            .long("no-disable-touchpad")
+            .help("Turn off disable-touchpad from the config file")
+            .overrides_with("touchpad")
+            .takes_value(false),
         Arg::with_name("version")
             .long("version")
             .short('V')
@@ -113,7 +620,7 @@ fn main() -> Result<(), String> {
             .long("beforehooks")
             .short('b')
             .value_name("BEFOREHOOKS")
-            .help("Run hook(s) before screen rotation. Passes $ORIENTATION and $PREV_ORIENTATION to hooks. Comma-seperated.")
+            .help("Run hook(s) before screen rotation or posture changes. Passes $ORIENTATION, $PREV_ORIENTATION, $POSTURE, $PREV_POSTURE, $HINGE_ANGLE and $KEYBOARD_ATTACHED to hooks. Comma-seperated.")
             .takes_value(true)
             .use_value_delimiter(true)
             .require_value_delimiter(true),
@@ -121,207 +628,111 @@ fn main() -> Result<(), String> {
             .long("hooks")
             .short('h')
             .value_name("HOOKS")
-            .help("Run hook(s) after screen rotation. Passes $ORIENTATION and $PREV_ORIENTATION to hooks. Comma-seperated.")
+            .help("Run hook(s) after screen rotation or posture changes. Passes $ORIENTATION, $PREV_ORIENTATION, $POSTURE, $PREV_POSTURE, $HINGE_ANGLE and $KEYBOARD_ATTACHED to hooks. Comma-seperated.")
             .takes_value(true)
             .use_value_delimiter(true)
             .require_value_delimiter(true)
     ];
 
-    let cmd_lines = App::new("rot8").version(ROT8_VERSION).args(&args);
-
-    let matches = cmd_lines.get_matches();
-
-    if matches.is_present("version") {
-        println!("{}", ROT8_VERSION);
-        return Ok(());
-    }
-
-    let oneshot = matches.is_present("oneshot");
-    let sleep = matches.value_of("sleep").unwrap_or("default.conf");
-    let display = matches.value_of("display").unwrap_or("default.conf");
-    let device = matches.value_of("device").unwrap();
-    let touchscreens: Vec<String> = matches.get_many("touchscreen").unwrap().cloned().collect();
-    let hooks: Vec<&str> = matches.values_of("hooks").unwrap_or_default().collect();
-    let beforehooks: Vec<&str> = matches
-        .values_of("beforehooks")
-        .unwrap_or_default()
-        .collect();
-    let disable_keyboard = matches.is_present("keyboard");
-    let threshold = matches.value_of("threshold").unwrap_or("default.conf");
-
-    let flip_x = matches.is_present("invert-x");
-    let flip_y = matches.is_present("invert-y");
-    let flip_z = matches.is_present("invert-z");
-    let mut xy = matches.value_of("invert-xy").unwrap_or("xy").chars();
-    let x_source = xy.next().unwrap();
-    let y_source = xy.next().unwrap();
-
-    let mut normalization_factor: Option<f32> = None;
-    if let Some(v) = matches.value_of("normalization-factor") {
-        match v.parse::<f32>() {
-            Ok(p) => normalization_factor = Some(p),
-            Err(_) => {
-                return Err(
-                    "The argument 'normalization-factor' is no valid float literal".to_string(),
-                );
-            }
-        }
-    }
+    App::new("rot8").version(ROT8_VERSION).args(&args)
+}
 
-    let mut backend: Box<dyn DisplayManager> = match WaylandBackend::new(display) {
+fn build_backend(config: &Config) -> Result<Box<dyn DisplayManager>, String> {
+    match WaylandBackend::new(&config.display) {
         Ok(wayland_backend) => {
             if process_exists("sway") {
-                Box::new(SwayBackend::new(wayland_backend, disable_keyboard))
+                Ok(Box::new(SwayBackend::new(
+                    wayland_backend,
+                    &config.display,
+                    InputFilter::new(&config.touchscreens, &config.touchscreen_exclude)?,
+                    config.disable_rules(),
+                    compile_patterns(&config.keep_inputs)?,
+                )))
             } else {
-                Box::new(wayland_backend)
+                Ok(Box::new(wayland_backend))
             }
         }
         Err(e) => {
             if process_exists("Xorg") || process_exists("X") {
-                Box::new(XorgBackend::new(display, touchscreens))
+                let touch_filter =
+                    || InputFilter::new(&config.touchscreens, &config.touchscreen_exclude);
+                match X11Backend::new(&config.display, touch_filter()?) {
+                    Ok(backend) => Ok(Box::new(backend)),
+                    Err(e) => {
+                        eprintln!("{}, falling back to xrandr", e);
+                        Ok(Box::new(XorgBackend::new(&config.display, touch_filter()?)))
+                    }
+                }
             } else {
-                return Err(format!(
+                Err(format!(
                     "Unable to find supported Xorg process or wayland compositor: {}.",
                     e
-                ));
+                ))
             }
         }
-    };
+    }
+}
 
-    for entry in glob(&(device.to_owned() + "/in_accel_*_raw")).unwrap() {
-        match entry {
-            Ok(path) => {
-                if path.to_str().unwrap().contains("x_raw") {
-                    path_x = path.to_str().unwrap().to_owned();
-                } else if path.to_str().unwrap().contains("y_raw") {
-                    path_y = path.to_str().unwrap().to_owned();
-                } else if path.to_str().unwrap().contains("z_raw") {
-                    path_z = path.to_str().unwrap().to_owned();
-                }
-            }
-            Err(e) => println!("{:?}", e),
+/// Build the accelerometer source and the posture detector, which share the
+/// lid accelerometer.
+fn build_sensors(
+    config: &Config,
+) -> Result<(Box<dyn AccelerometerSource>, Option<PostureDetector>), String> {
+    let interval = Duration::from_millis(config.sleep);
+    let lid = match config.sensor {
+        SensorKind::SensorProxy => None,
+        _ => Some(find_accelerometer(&config.device)?),
+    };
+    let source: Box<dyn AccelerometerSource> = match (config.sensor, &lid) {
+        (SensorKind::Iio, Some(lid)) => Box::new(IioPollingSource::new(lid, interval)?),
+        (SensorKind::IioBuffer, Some(lid)) => Box::new(IioBufferSource::new(lid, interval)?),
+        _ => {
+            let bus = zbus::blocking::Connection::system()
+                .map_err(|e| format!("Unable to connect to the system bus: {}", e))?;
+            Box::new(SensorProxySource::new(&bus, interval)?)
         }
+    };
+    Ok((source, build_posture_detector(config, lid.as_deref())?))
+}
+
+/// Posture detection needs a hinge sensor or a base accelerometer next to
+/// the lid one, the latter only when reading IIO devices directly.
+fn build_posture_detector(
+    config: &Config,
+    lid: Option<&Path>,
+) -> Result<Option<PostureDetector>, String> {
+    let hinge_device = match config.hinge_device.as_deref() {
+        Some("none") => None,
+        device => find_hinge(device)?,
+    };
+    let base_device = match (config.base_device.as_deref(), lid) {
+        (_, None) | (Some("none"), _) => None,
+        (Some(device), _) => Some(find_accelerometer(device)?),
+        (None, Some(lid)) => find_base_accelerometer(lid),
+    };
+    if base_device.is_none() && hinge_device.is_none() {
+        return Ok(None);
     }
+    Ok(Some(PostureDetector::new(
+        base_device.as_deref(),
+        hinge_device.as_deref(),
+        source_axes(config),
+    )?))
+}
 
-    if !Path::new(&path_x).exists() && !Path::new(&path_y).exists() && !Path::new(&path_z).exists()
-    {
-        Err("Unknown Accelerometer Device".to_string())
+fn build_detachable(config: &Config) -> Result<Option<DetachableKeyboard>, String> {
+    if config.detachable_keyboards.is_empty() {
+        Ok(None)
     } else {
-        let orientations = [
-            Orientation {
-                vector: (0.0, -1.0),
-                wayland_state: Transform::Normal,
-                x_state: "normal",
-                matrix: ["1", "0", "0", "0", "1", "0", "0", "0", "1"],
-            },
-            Orientation {
-                vector: (0.0, 1.0),
-                wayland_state: Transform::_180,
-                x_state: "inverted",
-                matrix: ["-1", "0", "1", "0", "-1", "1", "0", "0", "1"],
-            },
-            Orientation {
-                vector: (-1.0, 0.0),
-                wayland_state: Transform::_270,
-                x_state: "right",
-                matrix: ["0", "1", "0", "-1", "0", "1", "0", "0", "1"],
-            },
-            Orientation {
-                vector: (1.0, 0.0),
-                wayland_state: Transform::_90,
-                x_state: "left",
-                matrix: ["0", "-1", "1", "1", "0", "0", "0", "0", "1"],
-            },
-        ];
-
-        let mut old_state = backend.get_rotation_state()?;
-        let mut current_orient: &Orientation = &orientations[0];
-
-        loop {
-            let x_raw = fs::read_to_string(path_x.as_str()).unwrap();
-            let y_raw = fs::read_to_string(path_y.as_str()).unwrap();
-            let z_raw = fs::read_to_string(path_z.as_str()).unwrap();
-            let x_clean = x_raw.trim_end_matches('\n').parse::<f32>().unwrap_or(0.);
-            let y_clean = y_raw.trim_end_matches('\n').parse::<f32>().unwrap_or(0.);
-            let z_clean = z_raw.trim_end_matches('\n').parse::<f32>().unwrap_or(0.);
-
-            // Normalize vectors
-            let norm_factor = normalization_factor.unwrap_or_else(|| {
-                f32::sqrt(x_clean * x_clean + y_clean * y_clean + z_clean * z_clean)
-            });
-
-            let mut mut_x: f32 = x_clean / norm_factor;
-            let mut mut_y: f32 = y_clean / norm_factor;
-            let mut mut_z: f32 = z_clean / norm_factor;
-
-            // Apply inversions
-            if flip_x {
-                mut_x = -mut_x;
-            }
-            if flip_y {
-                mut_y = -mut_y;
-            }
-            if flip_z {
-                mut_z = -mut_z;
-            }
-            // Switch axes as requested
-            let x = match x_source {
-                'y' => mut_y,
-                'z' => mut_z,
-                _ => mut_x,
-            };
-            let y = match y_source {
-                'x' => mut_x,
-                'z' => mut_z,
-                _ => mut_y,
-            };
-
-            for orient in orientations.iter() {
-                let d = (x - orient.vector.0).powf(2.0) + (y - orient.vector.1).powf(2.0);
-                if d < threshold.parse::<f32>().unwrap_or(0.5) {
-                    current_orient = orient;
-                    break;
-                }
-            }
-
-            if current_orient.wayland_state != old_state {
-                let old_env = transform_to_env(&old_state);
-                let new_env = transform_to_env(&current_orient.wayland_state);
-                for bhook in beforehooks.iter() {
-                    Command::new("bash")
-                        .arg("-c")
-                        .arg(bhook)
-                        .env("ORIENTATION", new_env)
-                        .env("PREV_ORIENTATION", old_env)
-                        .spawn()
-                        .expect("A hook failed to start.")
-                        .wait()
-                        .expect("Waiting for a hook failed.");
-                }
-
-                backend.change_rotation_state(current_orient);
-
-                for hook in hooks.iter() {
-                    Command::new("bash")
-                        .arg("-c")
-                        .arg(hook)
-                        .env("ORIENTATION", new_env)
-                        .env("PREV_ORIENTATION", old_env)
-                        .spawn()
-                        .expect("A hook failed to start.")
-                        .wait()
-                        .expect("Waiting for a hook failed.");
-                }
-
-                old_state = current_orient.wayland_state;
-            }
-
-            if oneshot {
-                return Ok(());
-            }
+        Ok(Some(DetachableKeyboard::new(&config.detachable_keyboards)?))
+    }
+}
 
-            thread::sleep(Duration::from_millis(sleep.parse::<u64>().unwrap_or(0)));
-        }
+fn build_service(config: &Config) -> Result<Option<SensorProxyService>, String> {
+    if config.serve_sensor_proxy {
+        Ok(Some(SensorProxyService::new()?))
+    } else {
+        Ok(None)
     }
 }
 
@@ -346,3 +757,172 @@ fn transform_to_env(transform: &Transform) -> &str {
         _ => "normal",
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sensors::FakeSource;
+
+    fn sample(x: f32, y: f32, z: f32, physical: bool) -> Sample {
+        Sample {
+            x,
+            y,
+            z,
+            physical,
+            timestamp: Instant::now(),
+        }
+    }
+
+    /// Gravity along the given unit vector, in m/s².
+    fn gravity(x: f32, y: f32, z: f32) -> Sample {
+        sample(
+            x * STANDARD_GRAVITY,
+            y * STANDARD_GRAVITY,
+            z * STANDARD_GRAVITY,
+            true,
+        )
+    }
+
+    /// Replay samples like the main loop does, keeping the last orientation
+    /// while none is detected.
+    fn replay(samples: Vec<Sample>, config: &Config) -> Vec<Transform> {
+        let mut source = FakeSource::new(samples);
+        let axes = source_axes(config);
+        let mut current = &ORIENTATIONS[0];
+        let mut states = vec![];
+        while let Ok(sample) = source.read_sample() {
+            let [x, y, _] = screen_vector(&sample, config, &axes);
+            if let Some(orient) = detect_orientation(x, y, config.threshold) {
+                current = orient;
+            }
+            states.push(current.wayland_state);
+        }
+        states
+    }
+
+    #[test]
+    fn detects_each_orientation() {
+        let samples = vec![
+            gravity(0., -1., 0.),
+            gravity(0., 1., 0.),
+            gravity(-1., 0., 0.),
+            gravity(1., 0., 0.),
+        ];
+        assert_eq!(
+            replay(samples, &Config::default()),
+            [
+                Transform::Normal,
+                Transform::_180,
+                Transform::_270,
+                Transform::_90
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_orientation_when_flat_or_between() {
+        let diagonal = std::f32::consts::FRAC_1_SQRT_2;
+        let samples = vec![
+            gravity(-1., 0., 0.),
+            gravity(0., 0., -1.),
+            gravity(-diagonal, -diagonal, 0.),
+            gravity(0., -1., 0.),
+        ];
+        assert_eq!(
+            replay(samples, &Config::default()),
+            [
+                Transform::_270,
+                Transform::_270,
+                Transform::_270,
+                Transform::Normal
+            ]
+        );
+    }
+
+    #[test]
+    fn threshold_limits_tilt() {
+        // Upright and tilted 30° to the side
+        let tilted = || gravity(-0.5, -0.866, 0.);
+        let mut config = Config {
+            threshold: 0.5,
+            ..Config::default()
+        };
+        assert_eq!(
+            replay(vec![gravity(1., 0., 0.), tilted()], &config),
+            [Transform::_90, Transform::Normal]
+        );
+        config.threshold = 0.2;
+        assert_eq!(
+            replay(vec![gravity(1., 0., 0.), tilted()], &config),
+            [Transform::_90, Transform::_90]
+        );
+    }
+
+    #[test]
+    fn normalizes_readings() {
+        let config = Config::default();
+        // Physical readings are relative to gravity, so leaning back shortens y
+        let [x, y, z] = screen_vector(&gravity(0., -0.5, -0.866), &config, &Matrix3::IDENTITY);
+        assert!(x.abs() < 1e-6 && (y + 0.5).abs() < 1e-6 && (z + 0.866).abs() < 1e-6);
+
+        // Raw counts are scaled by their own magnitude
+        let [_, y, _] = screen_vector(&sample(0., -512., 0., false), &config, &Matrix3::IDENTITY);
+        assert!((y + 1.).abs() < 1e-6);
+
+        // unless a factor is given
+        let config = Config {
+            normalization_factor: Some(1024.),
+            ..Config::default()
+        };
+        let [_, y, _] = screen_vector(&sample(0., -512., 0., false), &config, &Matrix3::IDENTITY);
+        assert!((y + 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn axis_options_apply_to_samples() {
+        let config = Config {
+            invert_x: true,
+            invert_xy: "yx".into(),
+            ..Config::default()
+        };
+        // The hardware x axis becomes the screen y axis, inverted
+        assert_eq!(
+            replay(vec![gravity(1., 0., 0.)], &config),
+            [Transform::Normal]
+        );
+    }
+
+    #[test]
+    fn sensor_proxy_orientations_skip_axis_options() {
+        let config = Config {
+            sensor: SensorKind::SensorProxy,
+            invert_x: true,
+            invert_xy: "yx".into(),
+            ..Config::default()
+        };
+        assert_eq!(replay(vec![gravity(1., 0., 0.)], &config), [Transform::_90]);
+    }
+
+    #[test]
+    fn sensors_are_kept_across_unrelated_changes() {
+        let config = Config::default();
+        let changed = |new_config: Config| sensors_changed(&config, &new_config);
+        assert!(!changed(Config {
+            threshold: 0.9,
+            display: "DSI-1".into(),
+            ..Config::default()
+        }));
+        assert!(changed(Config {
+            sleep: 100,
+            ..Config::default()
+        }));
+        assert!(changed(Config {
+            sensor: SensorKind::IioBuffer,
+            ..Config::default()
+        }));
+        assert!(changed(Config {
+            invert_y: true,
+            ..Config::default()
+        }));
+    }
+}
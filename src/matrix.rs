@@ -0,0 +1,211 @@
+use std::fmt;
+use std::ops::Mul;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+/// A 3×3 matrix of row vectors, used to rotate accelerometer readings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Matrix3(pub [[f32; 3]; 3]);
+
+impl Matrix3 {
+    pub const IDENTITY: Matrix3 = Matrix3([[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]]);
+
+    /// Multiply a column vector by this matrix.
+    pub fn apply(&self, v: [f32; 3]) -> [f32; 3] {
+        let mut out = [0.; 3];
+        for (out, row) in out.iter_mut().zip(self.0.iter()) {
+            *out = row[0] * v[0] + row[1] * v[1] + row[2] * v[2];
+        }
+        out
+    }
+}
+
+impl Mul for Matrix3 {
+    type Output = Matrix3;
+
+    fn mul(self, other: Matrix3) -> Matrix3 {
+        let mut out = [[0.; 3]; 3];
+        for (i, row) in out.iter_mut().enumerate() {
+            for (j, cell) in row.iter_mut().enumerate() {
+                *cell = (0..3).map(|k| self.0[i][k] * other.0[k][j]).sum();
+            }
+        }
+        Matrix3(out)
+    }
+}
+
+/// Fit a touch coordinate transformation matrix, given in row-major order
+/// like `xinput` takes it, to an output at `(x, y, width, height)` of an X
+/// screen with the given size. The matrix maps touches onto the whole screen
+/// and is scaled and moved onto the output after rotating.
+pub fn fit_to_output(
+    matrix: [f32; 9],
+    (x, y, width, height): (i32, i32, i32, i32),
+    (screen_width, screen_height): (i32, i32),
+) -> [f32; 9] {
+    if screen_width <= 0 || screen_height <= 0 {
+        return matrix;
+    }
+    let (screen_width, screen_height) = (screen_width as f32, screen_height as f32);
+    let output = Matrix3([
+        [width as f32 / screen_width, 0., x as f32 / screen_width],
+        [0., height as f32 / screen_height, y as f32 / screen_height],
+        [0., 0., 1.],
+    ]);
+    let rotation = Matrix3([
+        [matrix[0], matrix[1], matrix[2]],
+        [matrix[3], matrix[4], matrix[5]],
+        [matrix[6], matrix[7], matrix[8]],
+    ]);
+
+    let mut out = [0.; 9];
+    for (cell, value) in out.iter_mut().zip((output * rotation).0.concat()) {
+        *cell = value;
+    }
+    out
+}
+
+/// Parses the kernel's `mount_matrix` format: three rows separated by `;`,
+/// with comma separated values, e.g. `0, 1, 0; -1, 0, 0; 0, 0, 1`.
+impl FromStr for Matrix3 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid 3x3 matrix '{}'", s.trim());
+        let mut matrix = [[0.; 3]; 3];
+        let rows: Vec<&str> = s.trim().split(';').collect();
+        if rows.len() != 3 {
+            return Err(invalid());
+        }
+        for (row, values) in matrix.iter_mut().zip(rows) {
+            let values: Vec<&str> = values.split(',').collect();
+            if values.len() != 3 {
+                return Err(invalid());
+            }
+            for (cell, value) in row.iter_mut().zip(values) {
+                *cell = value.trim().parse().map_err(|_| invalid())?;
+            }
+        }
+        Ok(Matrix3(matrix))
+    }
+}
+
+impl fmt::Display for Matrix3 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows: Vec<String> = self
+            .0
+            .iter()
+            .map(|row| format!("{}, {}, {}", row[0], row[1], row[2]))
+            .collect();
+        write!(f, "{}", rows.join("; "))
+    }
+}
+
+/// Accepts either the string format or a list of three rows.
+impl<'de> Deserialize<'de> for Matrix3 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Text(String),
+            Rows([[f32; 3]; 3]),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Text(text) => text.parse().map_err(serde::de::Error::custom),
+            Repr::Rows(rows) => Ok(Matrix3(rows)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The rotations rot8 sets for each orientation, with the corner of the
+    // output where touches at (0, 0) and (1, 0) of the panel land.
+    const NORMAL: [f32; 9] = [1., 0., 0., 0., 1., 0., 0., 0., 1.];
+    const INVERTED: [f32; 9] = [-1., 0., 1., 0., -1., 1., 0., 0., 1.];
+    const RIGHT: [f32; 9] = [0., 1., 0., -1., 0., 1., 0., 0., 1.];
+    const LEFT: [f32; 9] = [0., -1., 1., 1., 0., 0., 0., 0., 1.];
+
+    /// Where a touch at `(tx, ty)` lands on the screen, in pixels.
+    fn touch(matrix: [f32; 9], (tx, ty): (f32, f32), screen: (i32, i32)) -> (f32, f32) {
+        let m = Matrix3([
+            [matrix[0], matrix[1], matrix[2]],
+            [matrix[3], matrix[4], matrix[5]],
+            [matrix[6], matrix[7], matrix[8]],
+        ]);
+        let [x, y, _] = m.apply([tx, ty, 1.]);
+        (x * screen.0 as f32, y * screen.1 as f32)
+    }
+
+    fn assert_near(actual: (f32, f32), expected: (i32, i32)) {
+        assert!(
+            (actual.0 - expected.0 as f32).abs() < 0.5
+                && (actual.1 - expected.1 as f32).abs() < 0.5,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    fn check(rotation: [f32; 9], output: (i32, i32, i32, i32), screen: (i32, i32)) {
+        let (x, y, w, h) = output;
+        let (origin, x_axis) = match rotation {
+            NORMAL => ((x, y), (x + w, y)),
+            INVERTED => ((x + w, y + h), (x, y + h)),
+            RIGHT => ((x, y + h), (x, y)),
+            LEFT => ((x + w, y), (x + w, y + h)),
+            _ => unreachable!(),
+        };
+        let matrix = fit_to_output(rotation, output, screen);
+        assert_near(touch(matrix, (0., 0.), screen), origin);
+        assert_near(touch(matrix, (1., 0.), screen), x_axis);
+        assert_near(touch(matrix, (0.5, 0.5), screen), (x + w / 2, y + h / 2));
+    }
+
+    #[test]
+    fn single_output_keeps_rotation() {
+        for rotation in [NORMAL, INVERTED, RIGHT, LEFT].iter() {
+            let size = if *rotation == RIGHT || *rotation == LEFT {
+                (1080, 1920)
+            } else {
+                (1920, 1080)
+            };
+            let matrix = fit_to_output(*rotation, (0, 0, size.0, size.1), size);
+            assert_eq!(matrix, *rotation);
+            check(*rotation, (0, 0, size.0, size.1), size);
+        }
+    }
+
+    #[test]
+    fn output_left_of_external() {
+        check(NORMAL, (0, 0, 1920, 1080), (4480, 1440));
+        check(INVERTED, (0, 0, 1920, 1080), (4480, 1440));
+        check(RIGHT, (0, 0, 1080, 1920), (3640, 1920));
+        check(LEFT, (0, 0, 1080, 1920), (3640, 1920));
+    }
+
+    #[test]
+    fn output_right_of_external() {
+        check(NORMAL, (2560, 360, 1920, 1080), (4480, 1440));
+        check(INVERTED, (2560, 360, 1920, 1080), (4480, 1440));
+        check(RIGHT, (2560, 0, 1080, 1920), (3640, 1920));
+        check(LEFT, (2560, 0, 1080, 1920), (3640, 1920));
+    }
+
+    #[test]
+    fn output_below_external() {
+        check(NORMAL, (320, 1440, 1920, 1080), (2560, 2520));
+        check(INVERTED, (320, 1440, 1920, 1080), (2560, 2520));
+        check(RIGHT, (320, 1440, 1080, 1920), (2560, 3360));
+        check(LEFT, (320, 1440, 1080, 1920), (2560, 3360));
+    }
+
+    #[test]
+    fn empty_screen_leaves_matrix() {
+        assert_eq!(fit_to_output(LEFT, (0, 0, 1080, 1920), (0, 0)), LEFT);
+    }
+}
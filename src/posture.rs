@@ -0,0 +1,278 @@
+use std::path::Path;
+use std::time::Duration;
+
+use crate::matrix::Matrix3;
+use crate::sensors::hinge::HingeSensor;
+use crate::sensors::iio::IioPollingSource;
+use crate::sensors::AccelerometerSource;
+
+/// How far the hinge angle has to move past a threshold before the posture
+/// changes, so that holding the device near a boundary does not flap.
+const HYSTERESIS: f32 = 10.;
+
+/// How a convertible is folded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Posture {
+    /// Regular clamshell use, including closed.
+    Laptop,
+    /// Folded past flat and standing on both edges, with the hinge on top.
+    Tent,
+    /// Folded past flat with the keyboard face down on the table.
+    Stand,
+    /// Folded all the way around.
+    Tablet,
+}
+
+impl Posture {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Posture::Laptop => "laptop",
+            Posture::Tent => "tent",
+            Posture::Stand => "stand",
+            Posture::Tablet => "tablet",
+        }
+    }
+
+    /// Whether the keyboard is out of reach in this posture.
+    pub fn is_tablet_like(&self) -> bool {
+        *self != Posture::Laptop
+    }
+
+    /// Classify the posture from the hinge angle in degrees and the
+    /// normalized gravity vectors of the base, if known, and the lid.
+    fn classify(
+        hinge_angle: f32,
+        base: Option<[f32; 3]>,
+        lid: [f32; 3],
+        previous: Option<Posture>,
+    ) -> Posture {
+        // Widen the range of the current posture
+        let (laptop_max, tablet_min) = match previous {
+            Some(Posture::Laptop) => (200. + HYSTERESIS, 330.),
+            Some(Posture::Tablet) => (200., 330. - HYSTERESIS),
+            Some(_) => (200. - HYSTERESIS, 330. + HYSTERESIS),
+            None => (200., 330.),
+        };
+
+        if hinge_angle < laptop_max {
+            Posture::Laptop
+        } else if hinge_angle > tablet_min {
+            Posture::Tablet
+        } else {
+            let stand = match base {
+                // The keyboard lies flat, so the lid stands upright on it
+                Some(base) => base[2].abs() > 0.8,
+                // Only in tent posture is the screen upside down
+                None => lid[1] < 0.,
+            };
+            if stand {
+                Posture::Stand
+            } else {
+                Posture::Tent
+            }
+        }
+    }
+}
+
+/// Angle between base and lid around the hinge in degrees, 0 when closed and
+/// 180 when opened flat. Both vectors must be normalized and expressed in
+/// frames that coincide when the device lies open flat, with the hinge along
+/// the x axis.
+///
+/// Returns `None` when the hinge points up or down, because gravity then
+/// says nothing about the angle.
+pub fn hinge_angle(base: [f32; 3], lid: [f32; 3]) -> Option<f32> {
+    if base[0].abs() > 0.85 || lid[0].abs() > 0.85 {
+        return None;
+    }
+
+    // Project both onto the plane perpendicular to the hinge
+    let (by, bz) = (base[1], base[2]);
+    let (ly, lz) = (lid[1], lid[2]);
+    let dot = by * ly + bz * lz;
+    let cross = by * lz - bz * ly;
+    let clockwise = (-cross).atan2(dot).to_degrees().rem_euclid(360.);
+    Some((180. - clockwise).rem_euclid(360.))
+}
+
+/// Tracks the posture from the lid readings and either a hinge angle sensor
+/// or the base accelerometer, preferring the hinge sensor for the angle.
+pub struct PostureDetector {
+    base: Option<IioPollingSource>,
+    /// Axis transform applied to the lid readings, applied to the base too
+    /// so that both end up in the same frame.
+    axes: Matrix3,
+    hinge: Option<HingeSensor>,
+    hinge_angle: Option<f32>,
+    posture: Option<Posture>,
+}
+
+impl PostureDetector {
+    /// Set up detection with the accelerometer in the base and/or a hinge
+    /// sensor. At least one of them is needed.
+    pub fn new(
+        base_device: Option<&Path>,
+        hinge_device: Option<&Path>,
+        axes: Matrix3,
+    ) -> Result<Self, String> {
+        let base = match base_device {
+            Some(device) => {
+                println!("Using base accelerometer {}", device.display());
+                Some(IioPollingSource::new(device, Duration::ZERO)?)
+            }
+            None => None,
+        };
+        let hinge = match hinge_device {
+            Some(device) => Some(HingeSensor::new(device)?),
+            None => None,
+        };
+        if base.is_none() && hinge.is_none() {
+            return Err("Posture detection needs a base accelerometer or hinge sensor".to_string());
+        }
+        Ok(PostureDetector {
+            base,
+            axes,
+            hinge,
+            hinge_angle: None,
+            posture: None,
+        })
+    }
+
+    /// Update the posture from a normalized lid reading in screen axes.
+    pub fn update(&mut self, lid: [f32; 3]) -> Result<Option<Posture>, String> {
+        let lid = normalize(lid);
+        let base = match &mut self.base {
+            Some(source) => {
+                let sample = source.read_sample()?;
+                Some(normalize(self.axes.apply([sample.x, sample.y, sample.z])))
+            }
+            None => None,
+        };
+        self.hinge_angle = match (&self.hinge, base) {
+            (Some(hinge), _) => Some(hinge.read_angle()?),
+            (None, Some(base)) => hinge_angle(base, lid),
+            (None, None) => None,
+        };
+        if let Some(angle) = self.hinge_angle {
+            self.posture = Some(Posture::classify(angle, base, lid, self.posture));
+        }
+        Ok(self.posture)
+    }
+
+    /// The hinge angle in degrees from the last update, if it could be measured.
+    pub fn hinge_angle(&self) -> Option<f32> {
+        self.hinge_angle
+    }
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let norm = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    if norm == 0. {
+        return v;
+    }
+    [v[0] / norm, v[1] / norm, v[2] / norm]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: [f32; 3] = [0., 0., -1.];
+
+    /// Gravity as seen by the lid when opened `angle` degrees from the base
+    /// lying flat.
+    fn lid(angle: f32) -> [f32; 3] {
+        let a = angle.to_radians();
+        [0., -a.sin(), a.cos()]
+    }
+
+    /// Rotate a vector about the hinge, as when the whole device is tilted.
+    fn roll(v: [f32; 3], angle: f32) -> [f32; 3] {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        [v[0], v[1] * cos - v[2] * sin, v[1] * sin + v[2] * cos]
+    }
+
+    fn assert_angle(base: [f32; 3], lid: [f32; 3], expected: f32) {
+        let angle = hinge_angle(base, lid).unwrap();
+        let diff = (angle - expected + 180.).rem_euclid(360.) - 180.;
+        assert!(diff.abs() < 0.01, "expected {}, got {}", expected, angle);
+    }
+
+    #[test]
+    fn hinge_angle_follows_lid() {
+        assert_angle(BASE, lid(180.), 180.);
+        assert_angle(BASE, lid(90.), 90.);
+        assert_angle(BASE, lid(270.), 270.);
+        assert_angle(BASE, [0., -1., 0.], 90.);
+    }
+
+    #[test]
+    fn hinge_angle_ignores_device_tilt() {
+        for tilt in [-60., -20., 30., 75.] {
+            for angle in [0., 45., 90., 180., 270., 330.] {
+                assert_angle(roll(BASE, tilt), roll(lid(angle), tilt), angle);
+            }
+        }
+    }
+
+    #[test]
+    fn hinge_angle_unknown_when_hinge_is_vertical() {
+        assert_eq!(hinge_angle([1., 0., 0.], [1., 0., 0.]), None);
+        assert_eq!(hinge_angle(BASE, [-0.9, 0.1, 0.]), None);
+    }
+
+    #[test]
+    fn classifies_postures() {
+        let tilted = roll(BASE, 60.);
+        assert_eq!(
+            Posture::classify(0., Some(BASE), lid(0.), None),
+            Posture::Laptop
+        );
+        assert_eq!(
+            Posture::classify(100., Some(BASE), lid(100.), None),
+            Posture::Laptop
+        );
+        assert_eq!(
+            Posture::classify(180., Some(BASE), lid(180.), None),
+            Posture::Laptop
+        );
+        assert_eq!(
+            Posture::classify(250., Some(BASE), lid(250.), None),
+            Posture::Stand
+        );
+        assert_eq!(
+            Posture::classify(250., Some(tilted), lid(250.), None),
+            Posture::Tent
+        );
+        assert_eq!(
+            Posture::classify(350., Some(BASE), lid(350.), None),
+            Posture::Tablet
+        );
+    }
+
+    #[test]
+    fn classifies_without_base_by_lid_orientation() {
+        assert_eq!(
+            Posture::classify(250., None, [0., -1., 0.], None),
+            Posture::Stand
+        );
+        assert_eq!(
+            Posture::classify(250., None, [0., 1., 0.], None),
+            Posture::Tent
+        );
+    }
+
+    #[test]
+    fn hysteresis_keeps_posture_near_boundaries() {
+        let classify = |angle, previous| Posture::classify(angle, Some(BASE), lid(angle), previous);
+        assert_eq!(classify(205., None), Posture::Stand);
+        assert_eq!(classify(205., Some(Posture::Laptop)), Posture::Laptop);
+        assert_eq!(classify(215., Some(Posture::Laptop)), Posture::Stand);
+        assert_eq!(classify(195., Some(Posture::Stand)), Posture::Stand);
+        assert_eq!(classify(185., Some(Posture::Stand)), Posture::Laptop);
+        assert_eq!(classify(335., Some(Posture::Stand)), Posture::Stand);
+        assert_eq!(classify(345., Some(Posture::Stand)), Posture::Tablet);
+        assert_eq!(classify(325., Some(Posture::Tablet)), Posture::Tablet);
+        assert_eq!(classify(315., Some(Posture::Tablet)), Posture::Stand);
+    }
+}
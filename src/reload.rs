@@ -0,0 +1,173 @@
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+use signal_hook::consts::SIGHUP;
+
+/// Signals when the configuration should be reloaded, either because the
+/// process received SIGHUP or because the config file was written.
+pub struct ConfigWatcher {
+    sighup: Arc<AtomicBool>,
+    inotify: Option<Inotify>,
+    path: Option<PathBuf>,
+    /// The config file's directory, or its nearest existing ancestor until
+    /// the directory is created.
+    watched: Option<(PathBuf, WatchDescriptor)>,
+    buffer: [u8; 4096],
+}
+
+impl ConfigWatcher {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let sighup = Arc::new(AtomicBool::new(false));
+        if let Err(e) = signal_hook::flag::register(SIGHUP, Arc::clone(&sighup)) {
+            eprintln!("Unable to listen for SIGHUP: {}", e);
+        }
+
+        let inotify = match path {
+            Some(_) => Inotify::init()
+                .map_err(|e| eprintln!("Unable to watch the config file: {}", e))
+                .ok(),
+            None => None,
+        };
+        let mut watcher = ConfigWatcher {
+            sighup,
+            inotify,
+            path,
+            watched: None,
+            buffer: [0; 4096],
+        };
+        watcher.watch();
+        watcher
+    }
+
+    /// Watch the directory rather than the file, so that editors which
+    /// replace the file on save and files created later are picked up too.
+    fn watch(&mut self) {
+        let (inotify, path) = match (&mut self.inotify, &self.path) {
+            (Some(inotify), Some(path)) => (inotify, path),
+            _ => return,
+        };
+        let dir = match path
+            .ancestors()
+            .skip(1)
+            .find(|d| dir_or_current(d).is_dir())
+        {
+            Some(dir) => dir_or_current(dir).to_path_buf(),
+            None => return,
+        };
+        if self.watched.as_ref().map(|(d, _)| d) == Some(&dir) {
+            return;
+        }
+
+        if let Some((_, wd)) = self.watched.take() {
+            // Fails when the directory is gone, which already removed the watch
+            let _ = inotify.watches().remove(wd);
+        }
+        match inotify.watches().add(
+            &dir,
+            WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE,
+        ) {
+            Ok(wd) => self.watched = Some((dir, wd)),
+            Err(e) => eprintln!("Unable to watch {}: {}", dir.display(), e),
+        }
+    }
+
+    /// Whether the config file's own directory is watched.
+    fn watching_parent(&self) -> bool {
+        match (&self.watched, self.path.as_deref().and_then(Path::parent)) {
+            (Some((dir, _)), Some(parent)) => dir == dir_or_current(parent),
+            _ => false,
+        }
+    }
+
+    /// Whether a reload was requested since the last call. Never blocks.
+    pub fn reload_requested(&mut self) -> bool {
+        let mut requested = self.sighup.swap(false, Ordering::Relaxed);
+
+        let (changed, written) = match self.read_events() {
+            Ok(events) => events,
+            Err(e) => {
+                eprintln!("Unable to read config file changes: {}", e);
+                self.inotify = None;
+                (false, false)
+            }
+        };
+        if self.watching_parent() {
+            requested |= written;
+        } else if changed {
+            // Something was created on the way to the config file
+            self.watch();
+            requested |= self.watching_parent() && self.path.as_ref().is_some_and(|p| p.exists());
+        }
+
+        requested
+    }
+
+    /// Drain pending events, telling whether there were any and whether any
+    /// was about the config file.
+    fn read_events(&mut self) -> io::Result<(bool, bool)> {
+        let inotify = match &mut self.inotify {
+            Some(inotify) => inotify,
+            None => return Ok((false, false)),
+        };
+        let file_name = self.path.as_deref().and_then(Path::file_name);
+        let (mut changed, mut written) = (false, false);
+        loop {
+            match inotify.read_events(&mut self.buffer) {
+                Ok(events) => {
+                    let mut empty = true;
+                    for event in events {
+                        empty = false;
+                        changed = true;
+                        if event.name.is_some() && event.name == file_name {
+                            written = true;
+                        }
+                    }
+                    if empty {
+                        return Ok((changed, written));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok((changed, written)),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A relative config path without directory lives in the current directory.
+fn dir_or_current(dir: &Path) -> &Path {
+    if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn follows_config_directory_created_later() {
+        let base = std::env::temp_dir().join(format!("rot8-reload-{}", std::process::id()));
+        fs::create_dir_all(&base).unwrap();
+        let path = base.join("rot8").join("config.toml");
+
+        let mut watcher = ConfigWatcher::new(Some(path.clone()));
+        assert_eq!(watcher.watched.as_ref().map(|(d, _)| d), Some(&base));
+        assert!(!watcher.reload_requested());
+
+        fs::create_dir(base.join("rot8")).unwrap();
+        assert!(!watcher.reload_requested());
+        assert!(watcher.watching_parent());
+
+        fs::write(&path, "sleep = 100\n").unwrap();
+        assert!(watcher.reload_requested());
+        assert!(!watcher.reload_requested());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+use std::path::{Path, PathBuf};
+
+use glob::glob;
+
+use super::iio::{device_number, read_attr};
+
+/// Channels that may hold the hinge angle, in order of preference.
+const ANGLE_CHANNELS: [&str; 2] = ["in_angl_raw", "in_angl0_raw"];
+
+/// Reads the hinge angle from an IIO angle channel, as exposed by
+/// hid-sensor-hub "hinge" devices.
+pub struct HingeSensor {
+    path: PathBuf,
+    scale: f32,
+    offset: f32,
+}
+
+impl HingeSensor {
+    pub fn new(device: &Path) -> Result<Self, String> {
+        let channel = ANGLE_CHANNELS
+            .iter()
+            .find(|channel| device.join(channel).exists())
+            .ok_or_else(|| format!("{} has no angle channel", device.display()))?;
+        let prefix = channel.trim_end_matches("_raw");
+        let attr = |names: [String; 2]| {
+            names
+                .iter()
+                .find_map(|name| read_attr(&device.join(name)).ok())
+                .and_then(|v| v.parse::<f32>().ok())
+        };
+
+        println!("Using hinge sensor {}", device.display());
+        Ok(HingeSensor {
+            path: device.join(channel),
+            scale: attr([format!("{}_scale", prefix), "in_angl_scale".to_string()]).unwrap_or(1.),
+            offset: attr([format!("{}_offset", prefix), "in_angl_offset".to_string()])
+                .unwrap_or(0.),
+        })
+    }
+
+    /// Read the angle between base and lid in degrees, 0 when closed. The IIO
+    /// ABI reports angles in radians once scaled.
+    pub fn read_angle(&self) -> Result<f32, String> {
+        let raw = read_attr(&self.path)?
+            .parse::<f32>()
+            .map_err(|e| format!("Invalid hinge angle in {}: {}", self.path.display(), e))?;
+        Ok(((raw + self.offset) * self.scale).to_degrees())
+    }
+}
+
+/// Resolve `--hinge-device` to an IIO device with an angle channel, either
+/// from a path (glob) or a `name`/`label`. Without a value the first hinge
+/// found is used, if any.
+pub fn find_hinge(device: Option<&str>) -> Result<Option<PathBuf>, String> {
+    let pattern = match device {
+        Some(device) if device.contains('/') => device,
+        _ => "/sys/bus/iio/devices/iio:device*",
+    };
+    let mut candidates: Vec<PathBuf> = glob(pattern)
+        .map_err(|e| format!("Invalid hinge device pattern: {}", e))?
+        .flatten()
+        .filter(|path| ANGLE_CHANNELS.iter().any(|c| path.join(c).exists()))
+        .filter(|path| match device {
+            Some(device) if !device.contains('/') => ["name", "label"]
+                .iter()
+                .any(|attr| read_attr(&path.join(attr)).is_ok_and(|v| v == device)),
+            _ => true,
+        })
+        .collect();
+    candidates.sort_by_key(|path| device_number(path));
+
+    match (candidates.into_iter().next(), device) {
+        (Some(path), _) => Ok(Some(path)),
+        (None, None) => Ok(None),
+        (None, Some(device)) => Err(format!("No hinge sensor '{}' found", device)),
+    }
+}
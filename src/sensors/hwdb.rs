@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use glob::Pattern;
+
+use super::iio::read_attr;
+use crate::matrix::Matrix3;
+
+/// Directories holding hwdb sources, highest priority first. A file in an
+/// earlier directory hides a file with the same name in later ones.
+const HWDB_DIRS: [&str; 4] = [
+    "/etc/udev/hwdb.d",
+    "/run/udev/hwdb.d",
+    "/usr/lib/udev/hwdb.d",
+    "/lib/udev/hwdb.d",
+];
+
+/// Quirks already looked up, by canonical device path. Matching the hwdb
+/// sources means reading all of them, so it is done once per device.
+static QUIRKS: Mutex<BTreeMap<PathBuf, Quirks>> = Mutex::new(BTreeMap::new());
+
+/// Per-device sensor quirks from systemd's `60-sensor.hwdb`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Quirks {
+    pub mount_matrix: Option<Matrix3>,
+    /// `ACCEL_LOCATION`, either `display` or `base`.
+    pub location: Option<String>,
+}
+
+impl Quirks {
+    /// Look up the quirks of an IIO device, preferring the properties udev
+    /// already imported and falling back to matching the hwdb sources.
+    pub fn for_device(device: &Path) -> Quirks {
+        let key = fs::canonicalize(device).unwrap_or_else(|_| device.to_path_buf());
+        if let Some(quirks) = QUIRKS.lock().unwrap().get(&key) {
+            return quirks.clone();
+        }
+        let quirks = Quirks::look_up(device);
+        QUIRKS.lock().unwrap().insert(key, quirks.clone());
+        quirks
+    }
+
+    fn look_up(device: &Path) -> Quirks {
+        let mut properties = udev_properties(device);
+        if !properties.contains_key("ACCEL_MOUNT_MATRIX") {
+            for (key, value) in hwdb_properties(device) {
+                properties.entry(key).or_insert(value);
+            }
+        }
+
+        Quirks::from_properties(&properties)
+    }
+
+    fn from_properties(properties: &HashMap<String, String>) -> Quirks {
+        Quirks {
+            mount_matrix: properties
+                .get("ACCEL_MOUNT_MATRIX")
+                .and_then(|v| match v.parse() {
+                    Ok(matrix) => Some(matrix),
+                    Err(e) => {
+                        eprintln!("Ignoring ACCEL_MOUNT_MATRIX: {}", e);
+                        None
+                    }
+                }),
+            location: properties.get("ACCEL_LOCATION").cloned(),
+        }
+    }
+}
+
+/// Properties stored by udev in its database, `/run/udev/data/c<major>:<minor>`.
+pub fn udev_properties(device: &Path) -> HashMap<String, String> {
+    let mut properties = HashMap::new();
+    let dev = match read_attr(&device.join("dev")) {
+        Ok(dev) => dev,
+        Err(_) => return properties,
+    };
+    if let Ok(data) = fs::read_to_string(format!("/run/udev/data/c{}", dev)) {
+        for line in data.lines() {
+            if let Some((key, value)) = line.strip_prefix("E:").and_then(|p| p.split_once('=')) {
+                properties.insert(key.to_string(), value.to_string());
+            }
+        }
+    }
+    properties
+}
+
+/// Properties from the hwdb sources matching this device, built the same
+/// way as the lookup keys in systemd's `60-sensor.rules`.
+fn hwdb_properties(device: &Path) -> HashMap<String, String> {
+    let dmi = read_attr(Path::new("/sys/class/dmi/id/modalias")).unwrap_or_default();
+    let mut keys = vec![];
+    if let Some(modalias) = parent_modalias(device) {
+        keys.push(format!("sensor:modalias:{}:{}", modalias, dmi));
+    }
+    if let Ok(name) = read_attr(&device.join("name")) {
+        keys.push(format!("sensor:{}:{}", name, dmi));
+    }
+
+    let mut properties = HashMap::new();
+    for file in hwdb_files() {
+        if let Ok(source) = fs::read_to_string(&file) {
+            for (key, value) in match_hwdb(&source, &keys) {
+                properties.insert(key, value);
+            }
+        }
+    }
+    properties
+}
+
+/// The `modalias` of the closest ancestor that has one (the i2c, usb or
+/// platform device the sensor hangs off).
+fn parent_modalias(device: &Path) -> Option<String> {
+    let device = fs::canonicalize(device).ok()?;
+    device
+        .ancestors()
+        .skip(1)
+        .find_map(|dir| read_attr(&dir.join("modalias")).ok())
+}
+
+/// All `*.hwdb` files, ordered by file name with overrides resolved.
+fn hwdb_files() -> Vec<PathBuf> {
+    let mut files = BTreeMap::new();
+    for dir in HWDB_DIRS.iter().rev() {
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().is_some_and(|e| e == "hwdb") {
+                    files.insert(entry.file_name(), path);
+                }
+            }
+        }
+    }
+    files.into_values().collect()
+}
+
+/// Collect the properties of every record in `source` with a match line
+/// matching one of `keys`. Later records override earlier ones.
+fn match_hwdb(source: &str, keys: &[String]) -> Vec<(String, String)> {
+    let mut properties = vec![];
+    let mut matched = false;
+    let mut in_properties = false;
+
+    for line in source.lines() {
+        let line = line.trim_end();
+        if line.starts_with('#') {
+            continue;
+        }
+        if line.is_empty() {
+            matched = false;
+            in_properties = false;
+        } else if line.starts_with(char::is_whitespace) {
+            in_properties = true;
+            if matched {
+                if let Some((key, value)) = line.trim().split_once('=') {
+                    properties.push((key.to_string(), value.to_string()));
+                }
+            }
+        } else {
+            // A match line after properties starts a new record
+            if in_properties {
+                matched = false;
+                in_properties = false;
+            }
+            // Skip the other hwdb namespaces, like the huge usb and pci
+            // vendor lists, without compiling their patterns
+            if !line.starts_with("sensor:") {
+                continue;
+            }
+            if let Ok(pattern) = Pattern::new(line) {
+                matched |= keys.iter().any(|key| pattern.matches(key));
+            }
+        }
+    }
+    properties
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::iio::read_mount_matrix;
+
+    const HWDB: &str = "\
+# Comments and blank lines are skipped
+
+sensor:modalias:acpi:BOSC0200*:dmi:*:svnLENOVO:pn81A7:*
+sensor:modalias:acpi:KIOX000A*:dmi:*:svnLENOVO:pn81A7:*
+ ACCEL_MOUNT_MATRIX=0, 1, 0; -1, 0, 0; 0, 0, 1
+
+sensor:modalias:acpi:BOSC0200*:dmi:*:svnLENOVO:pn81A8:*
+ ACCEL_MOUNT_MATRIX=-1, 0, 0; 0, -1, 0; 0, 0, 1
+
+sensor:modalias:acpi:BOSC0200*:dmi:*:svnLENOVO:pn81A7:*
+ ACCEL_LOCATION=base
+";
+
+    const DMI: &str = "dmi:bvnLENOVO:bvr7XCN:svnLENOVO:pn81A7:pvrLenovoYoga:";
+
+    fn keys(modalias: &str) -> Vec<String> {
+        vec![format!("sensor:modalias:{}:{}", modalias, DMI)]
+    }
+
+    #[test]
+    fn matches_modalias_and_dmi() {
+        let properties = match_hwdb(HWDB, &keys("acpi:BOSC0200:BOSC0200"));
+        assert_eq!(
+            properties,
+            [
+                (
+                    "ACCEL_MOUNT_MATRIX".to_string(),
+                    "0, 1, 0; -1, 0, 0; 0, 0, 1".to_string()
+                ),
+                ("ACCEL_LOCATION".to_string(), "base".to_string()),
+            ]
+        );
+
+        // Any of a record's match lines selects it
+        let properties = match_hwdb(HWDB, &keys("acpi:KIOX000A:KIOX000A"));
+        assert_eq!(properties.len(), 1);
+        assert_eq!(properties[0].0, "ACCEL_MOUNT_MATRIX");
+    }
+
+    #[test]
+    fn skips_entries_that_do_not_match() {
+        assert!(match_hwdb(HWDB, &keys("acpi:SMO8500:SMO8500")).is_empty());
+        let other_model = vec![format!(
+            "sensor:modalias:acpi:BOSC0200:BOSC0200:{}",
+            DMI.replace("pn81A7", "pn81B0")
+        )];
+        assert!(match_hwdb(HWDB, &other_model).is_empty());
+    }
+
+    #[test]
+    fn skips_other_namespaces() {
+        let source = "\
+usb:v1D6Bp0002*
+ ID_MODEL_FROM_DATABASE=2.0 root hub
+
+*:*BOSC0200*
+ ACCEL_MOUNT_MATRIX=1, 0, 0; 0, 1, 0; 0, 0, -1
+";
+        assert!(match_hwdb(source, &keys("acpi:BOSC0200:BOSC0200")).is_empty());
+
+        let source = format!("{}{}", source, HWDB);
+        assert_eq!(
+            match_hwdb(&source, &keys("acpi:BOSC0200:BOSC0200")),
+            match_hwdb(HWDB, &keys("acpi:BOSC0200:BOSC0200"))
+        );
+    }
+
+    #[test]
+    fn hwdb_matrix_takes_precedence_over_kernel() {
+        let device = std::env::temp_dir().join(format!("rot8-hwdb-{}", std::process::id()));
+        fs::create_dir_all(&device).unwrap();
+        fs::write(
+            device.join("in_accel_mount_matrix"),
+            "-1, 0, 0; 0, 1, 0; 0, 0, 1\n",
+        )
+        .unwrap();
+
+        let properties: HashMap<_, _> = match_hwdb(HWDB, &keys("acpi:BOSC0200:BOSC0200"))
+            .into_iter()
+            .collect();
+        let quirks = Quirks::from_properties(&properties);
+        assert_eq!(quirks.location.as_deref(), Some("base"));
+        assert_eq!(
+            read_mount_matrix(&device, &quirks),
+            Matrix3([[0., 1., 0.], [-1., 0., 0.], [0., 0., 1.]])
+        );
+        assert_eq!(
+            read_mount_matrix(&device, &Quirks::default()),
+            Matrix3([[-1., 0., 0.], [0., 1., 0.], [0., 0., 1.]])
+        );
+
+        fs::remove_dir_all(&device).unwrap();
+    }
+}
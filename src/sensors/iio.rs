@@ -0,0 +1,276 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use glob::glob;
+
+use super::hwdb::Quirks;
+use super::{AccelerometerSource, Sample};
+use crate::matrix::Matrix3;
+
+pub const AXES: [&str; 3] = ["x", "y", "z"];
+
+/// Conversion from raw channel values to m/s² in the device's frame,
+/// following the IIO ABI: `(raw + offset) * scale`, rotated by the mount matrix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    scale: [f32; 3],
+    offset: [f32; 3],
+    /// Whether the device reported a scale, so that values are in m/s².
+    physical: bool,
+    mount_matrix: Matrix3,
+}
+
+impl Calibration {
+    /// Read `in_accel_scale`/`in_accel_offset`, letting per-axis attributes
+    /// like `in_accel_x_scale` take precedence over the shared ones.
+    pub fn read(device: &Path) -> Self {
+        let attr = |name: &str| {
+            read_attr(&device.join(name))
+                .ok()
+                .and_then(|v| v.parse::<f32>().ok())
+        };
+        let shared_scale = attr("in_accel_scale");
+        let shared_offset = attr("in_accel_offset");
+
+        let mut calibration = Calibration {
+            scale: [1.; 3],
+            offset: [0.; 3],
+            physical: true,
+            mount_matrix: read_mount_matrix(device, &Quirks::for_device(device)),
+        };
+        for (i, axis) in AXES.iter().enumerate() {
+            match attr(&format!("in_accel_{}_scale", axis)).or(shared_scale) {
+                Some(scale) => calibration.scale[i] = scale,
+                None => calibration.physical = false,
+            }
+            if let Some(offset) = attr(&format!("in_accel_{}_offset", axis)).or(shared_offset) {
+                calibration.offset[i] = offset;
+            }
+        }
+        if !calibration.physical {
+            calibration.scale = [1.; 3];
+        }
+        calibration
+    }
+
+    /// Turn raw x, y and z values into a sample.
+    pub fn sample(&self, raw: [f32; 3]) -> Sample {
+        let [x, y, z] = self.mount_matrix.apply([
+            (raw[0] + self.offset[0]) * self.scale[0],
+            (raw[1] + self.offset[1]) * self.scale[1],
+            (raw[2] + self.offset[2]) * self.scale[2],
+        ]);
+        Sample {
+            x,
+            y,
+            z,
+            physical: self.physical,
+            timestamp: Instant::now(),
+        }
+    }
+}
+
+/// Find the matrix describing how the sensor sits in the device. Quirks from
+/// the hwdb take precedence over the matrix reported by the kernel.
+pub fn read_mount_matrix(device: &Path, quirks: &Quirks) -> Matrix3 {
+    if let Some(matrix) = quirks.mount_matrix {
+        println!("Applying hwdb mount matrix {}", matrix);
+        return matrix;
+    }
+    for attr in ["in_accel_mount_matrix", "mount_matrix"] {
+        let path = device.join(attr);
+        if !path.exists() {
+            continue;
+        }
+        match read_attr(&path).and_then(|v| v.parse::<Matrix3>()) {
+            Ok(matrix) => {
+                if matrix != Matrix3::IDENTITY {
+                    println!("Applying mount matrix {} from {}", matrix, path.display());
+                }
+                return matrix;
+            }
+            Err(e) => eprintln!("Ignoring mount matrix: {}", e),
+        }
+    }
+    Matrix3::IDENTITY
+}
+
+/// Where an accelerometer sits in a convertible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    /// In the lid, moving with the screen.
+    Display,
+    /// In the base, next to the keyboard.
+    Base,
+    Unknown,
+}
+
+impl Location {
+    /// Determine the location from the `location` or `label` attributes, or
+    /// the hwdb `ACCEL_LOCATION` quirk, along with where it came from.
+    pub fn of(device: &Path) -> (Location, String) {
+        let parse = |v: &str| match v {
+            "lid" | "display" | "accel-display" => Some(Location::Display),
+            "base" | "accel-base" => Some(Location::Base),
+            _ => None,
+        };
+        for attr in ["location", "label"] {
+            if let Ok(value) = read_attr(&device.join(attr)) {
+                if let Some(location) = parse(&value) {
+                    return (location, format!("{}={}", attr, value));
+                }
+            }
+        }
+        if let Some(value) = Quirks::for_device(device).location {
+            if let Some(location) = parse(&value) {
+                return (location, format!("ACCEL_LOCATION={}", value));
+            }
+        }
+        (Location::Unknown, "no location reported".to_string())
+    }
+}
+
+/// Resolve `--device` to a single accelerometer.
+///
+/// The value is either a path (glob) to IIO devices or the `name` or `label`
+/// of one. When several accelerometers match, the one in the display wins.
+pub fn find_accelerometer(device: &str) -> Result<PathBuf, String> {
+    let by_path = device.contains('/');
+    let pattern = if by_path {
+        device
+    } else {
+        "/sys/bus/iio/devices/iio:device*"
+    };
+
+    let mut candidates: Vec<PathBuf> = glob(pattern)
+        .map_err(|e| format!("Invalid accelerometer device pattern: {}", e))?
+        .flatten()
+        .filter(|path| {
+            AXES.iter()
+                .any(|a| path.join(format!("in_accel_{}_raw", a)).exists())
+        })
+        .filter(|path| {
+            by_path
+                || ["name", "label"]
+                    .iter()
+                    .any(|attr| read_attr(&path.join(attr)).is_ok_and(|v| v == device))
+        })
+        .collect();
+    candidates.sort_by_key(|path| device_number(path));
+
+    let mut chosen: Option<(PathBuf, Location, String)> = None;
+    for path in candidates {
+        let (location, reason) = Location::of(&path);
+        let better = match &chosen {
+            None => true,
+            Some((_, best, _)) => rank(location) > rank(*best),
+        };
+        if better {
+            chosen = Some((path, location, reason));
+        }
+    }
+
+    let (path, _, reason) = chosen.ok_or_else(|| match by_path {
+        true => "Unknown Accelerometer Device".to_string(),
+        false => format!("No accelerometer named '{}' found", device),
+    })?;
+    println!(
+        "Using accelerometer {} ({}): {}",
+        path.display(),
+        read_attr(&path.join("name")).unwrap_or_default(),
+        reason
+    );
+    Ok(path)
+}
+
+/// Find the accelerometer in the base of a convertible, other than `lid`.
+pub fn find_base_accelerometer(lid: &Path) -> Option<PathBuf> {
+    let mut candidates: Vec<PathBuf> = glob("/sys/bus/iio/devices/iio:device*")
+        .ok()?
+        .flatten()
+        .filter(|path| path.join("in_accel_x_raw").exists() && path != lid)
+        .collect();
+    candidates.sort_by_key(|path| device_number(path));
+    candidates
+        .into_iter()
+        .find(|path| Location::of(path).0 == Location::Base)
+}
+
+fn rank(location: Location) -> u8 {
+    match location {
+        Location::Display => 2,
+        Location::Unknown => 1,
+        Location::Base => 0,
+    }
+}
+
+/// The `N` of `iio:deviceN`, to keep the device order stable.
+pub fn device_number(path: &Path) -> u32 {
+    path.to_string_lossy()
+        .rsplit("iio:device")
+        .next()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(u32::MAX)
+}
+
+/// Polls the `in_accel_{x,y,z}_raw` sysfs attributes of an IIO device.
+pub struct IioPollingSource {
+    paths: [PathBuf; 3],
+    calibration: Calibration,
+    interval: Duration,
+    last_read: Option<Instant>,
+}
+
+impl IioPollingSource {
+    pub fn new(device: &Path, interval: Duration) -> Result<Self, String> {
+        let paths = AXES.map(|axis| device.join(format!("in_accel_{}_raw", axis)));
+
+        Ok(IioPollingSource {
+            paths,
+            calibration: Calibration::read(device),
+            interval,
+            last_read: None,
+        })
+    }
+}
+
+impl AccelerometerSource for IioPollingSource {
+    fn read_sample(&mut self) -> Result<Sample, String> {
+        if let Some(last_read) = self.last_read {
+            let elapsed = last_read.elapsed();
+            if elapsed < self.interval {
+                thread::sleep(self.interval - elapsed);
+            }
+        }
+        self.last_read = Some(Instant::now());
+
+        Ok(self.calibration.sample([
+            read_axis(&self.paths[0])?,
+            read_axis(&self.paths[1])?,
+            read_axis(&self.paths[2])?,
+        ]))
+    }
+}
+
+/// Read a single raw channel value. Missing axes read as zero.
+fn read_axis(path: &Path) -> Result<f32, String> {
+    if !path.exists() {
+        return Ok(0.);
+    }
+    let raw = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    Ok(raw.trim_end_matches('\n').parse::<f32>().unwrap_or(0.))
+}
+
+/// Read a sysfs attribute, without the trailing newline.
+pub fn read_attr(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path)
+        .map(|v| v.trim().to_string())
+        .map_err(|e| format!("Unable to read {}: {}", path.display(), e))
+}
+
+pub fn write_attr(path: &Path, value: &str) -> Result<(), String> {
+    fs::write(path, value).map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+}
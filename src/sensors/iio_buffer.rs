@@ -0,0 +1,582 @@
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use glob::glob;
+
+use super::iio::{device_number, read_attr, write_attr, Calibration, AXES};
+use super::{AccelerometerSource, Sample};
+
+/// Storage format of a single channel in a buffered scan, as described by
+/// `scan_elements/<channel>_type`, e.g. `le:s12/16>>4`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanType {
+    pub big_endian: bool,
+    pub signed: bool,
+    pub bits: u32,
+    pub storage_bits: u32,
+    pub repeat: u32,
+    pub shift: u32,
+}
+
+impl FromStr for ScanType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid scan element type '{}'", s);
+        let (endianness, rest) = s.trim().split_once(':').ok_or_else(invalid)?;
+        let big_endian = match endianness {
+            "be" => true,
+            "le" => false,
+            _ => return Err(invalid()),
+        };
+        let signed = match rest.chars().next() {
+            Some('s') => true,
+            Some('u') => false,
+            _ => return Err(invalid()),
+        };
+        let (format, shift) = rest[1..].split_once(">>").ok_or_else(invalid)?;
+        let (bits, storage) = format.split_once('/').ok_or_else(invalid)?;
+        let (storage_bits, repeat) = match storage.split_once('X') {
+            Some((storage_bits, repeat)) => (storage_bits, repeat),
+            None => (storage, "1"),
+        };
+
+        let number = |v: &str| v.parse::<u32>().map_err(|_| invalid());
+        let scan_type = ScanType {
+            big_endian,
+            signed,
+            bits: number(bits)?,
+            storage_bits: number(storage_bits)?,
+            repeat: number(repeat)?,
+            shift: number(shift)?,
+        };
+        if scan_type.storage_bits == 0
+            || !scan_type.storage_bits.is_multiple_of(8)
+            || scan_type.storage_bits > 64
+            || scan_type.bits == 0
+            || scan_type.bits + scan_type.shift > scan_type.storage_bits
+            || scan_type.repeat == 0
+        {
+            return Err(invalid());
+        }
+        Ok(scan_type)
+    }
+}
+
+impl ScanType {
+    /// Bytes this channel occupies in a scan, which is also its alignment.
+    pub fn bytes(&self) -> usize {
+        (self.storage_bits / 8 * self.repeat) as usize
+    }
+
+    /// Decode the first value of this channel from `data`.
+    pub fn decode(&self, data: &[u8]) -> i64 {
+        let storage = &data[..(self.storage_bits / 8) as usize];
+        let mut value: u64 = 0;
+        if self.big_endian {
+            for byte in storage {
+                value = (value << 8) | u64::from(*byte);
+            }
+        } else {
+            for byte in storage.iter().rev() {
+                value = (value << 8) | u64::from(*byte);
+            }
+        }
+
+        value >>= self.shift;
+        if self.bits < 64 {
+            value &= (1 << self.bits) - 1;
+        }
+        if self.signed && self.bits < 64 && value & (1 << (self.bits - 1)) != 0 {
+            (value | !((1 << self.bits) - 1)) as i64
+        } else {
+            value as i64
+        }
+    }
+}
+
+/// Position of every enabled channel inside a scan, following the kernel's
+/// rule that each element is aligned to its own size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanLayout {
+    /// Byte offset and type of the x, y and z channels.
+    pub axes: [(usize, ScanType); 3],
+    /// Byte offset and type of the `in_timestamp` channel, if enabled.
+    pub timestamp: Option<(usize, ScanType)>,
+    /// Total size of one scan, including padding.
+    pub scan_bytes: usize,
+}
+
+impl ScanLayout {
+    /// Compute the layout from all enabled channels as `(name, index, type)`.
+    pub fn new(channels: &[(String, u32, ScanType)]) -> Result<Self, String> {
+        let mut channels = channels.to_vec();
+        channels.sort_by_key(|(_, index, _)| *index);
+
+        let mut axes: [Option<(usize, ScanType)>; 3] = [None; 3];
+        let mut timestamp = None;
+        let mut offset = 0;
+        let mut largest = 1;
+        for (name, _, scan_type) in &channels {
+            let bytes = scan_type.bytes();
+            offset = align(offset, bytes);
+            if let Some(axis) = AXES.iter().position(|a| *name == format!("in_accel_{}", a)) {
+                axes[axis] = Some((offset, *scan_type));
+            } else if name == "in_timestamp" {
+                timestamp = Some((offset, *scan_type));
+            }
+            offset += bytes;
+            largest = largest.max(bytes);
+        }
+
+        match axes {
+            [Some(x), Some(y), Some(z)] => Ok(ScanLayout {
+                axes: [x, y, z],
+                timestamp,
+                scan_bytes: align(offset, largest),
+            }),
+            _ => Err("Buffer does not contain all accelerometer axes".to_string()),
+        }
+    }
+
+    /// Decode the raw x, y and z values of one scan.
+    pub fn decode(&self, scan: &[u8]) -> [i64; 3] {
+        let mut values = [0; 3];
+        for (value, (offset, scan_type)) in values.iter_mut().zip(self.axes.iter()) {
+            *value = scan_type.decode(&scan[*offset..]);
+        }
+        values
+    }
+
+    /// Decode the timestamp of one scan in nanoseconds, if it has one.
+    pub fn decode_timestamp(&self, scan: &[u8]) -> Option<i64> {
+        self.timestamp
+            .map(|(offset, scan_type)| scan_type.decode(&scan[offset..]))
+    }
+}
+
+fn align(offset: usize, size: usize) -> usize {
+    offset.div_ceil(size) * size
+}
+
+/// Reads accelerometer scans from the IIO character device in buffered mode,
+/// sleeping in the kernel until the sensor delivers new data.
+pub struct IioBufferSource {
+    file: File,
+    _setup: BufferSetup,
+    layout: ScanLayout,
+    calibration: Calibration,
+    /// Clock the kernel stamps scans with.
+    clock: libc::clockid_t,
+    buffer: Vec<u8>,
+    pending: usize,
+    interval: Duration,
+    last_sample: Sample,
+    started: bool,
+}
+
+impl IioBufferSource {
+    pub fn new(device: &Path, interval: Duration) -> Result<Self, String> {
+        if !device.join("scan_elements/in_accel_x_en").exists() {
+            return Err(format!(
+                "Accelerometer {} has no buffer support",
+                device.display()
+            ));
+        }
+        let device = device.to_path_buf();
+        let dev_name = device
+            .file_name()
+            .ok_or("Invalid accelerometer device path")?
+            .to_string_lossy()
+            .into_owned();
+
+        // The raw attributes are usually busy once the buffer runs, so take
+        // the first sample before enabling it.
+        let mut initial = [0.; 3];
+        for (value, axis) in initial.iter_mut().zip(AXES.iter()) {
+            *value = read_attr(&device.join(format!("in_accel_{}_raw", axis)))
+                .ok()
+                .and_then(|v| v.parse::<f32>().ok())
+                .unwrap_or(0.);
+        }
+
+        write_attr(&device.join("buffer/enable"), "0")?;
+        let mut setup = BufferSetup {
+            device: device.clone(),
+            enabled: vec![],
+            trigger: false,
+        };
+        for axis in AXES.iter() {
+            let path = device.join(format!("scan_elements/in_accel_{}_en", axis));
+            if read_attr(&path)? != "1" {
+                write_attr(&path, "1")?;
+                setup.enabled.push(path);
+            }
+        }
+        // Optional, samples are stamped on arrival without it
+        let path = device.join("scan_elements/in_timestamp_en");
+        if path.exists() && read_attr(&path)? != "1" && write_attr(&path, "1").is_ok() {
+            setup.enabled.push(path);
+        }
+        setup.trigger = set_trigger(&device)?;
+        let frequency = (1000. / interval.as_millis().max(1) as f64).max(1.);
+        for attr in ["in_accel_sampling_frequency", "sampling_frequency"] {
+            let path = device.join(attr);
+            if path.exists() {
+                if let Err(e) = write_attr(&path, &format!("{}", frequency)) {
+                    eprintln!("Unable to set sampling frequency: {}", e);
+                }
+                break;
+            }
+        }
+        let layout = ScanLayout::new(&enabled_channels(&device)?)?;
+        let calibration = Calibration::read(&device);
+        write_attr(&device.join("buffer/length"), "16")?;
+        write_attr(&device.join("buffer/enable"), "1")?;
+
+        let dev_path = Path::new("/dev").join(&dev_name);
+        let file = File::open(&dev_path)
+            .map_err(|e| format!("Unable to open {}: {}", dev_path.display(), e))?;
+
+        Ok(IioBufferSource {
+            file,
+            _setup: setup,
+            buffer: vec![0; layout.scan_bytes * 16],
+            layout,
+            calibration,
+            clock: timestamp_clock(&device),
+            pending: 0,
+            interval,
+            last_sample: calibration.sample(initial),
+            started: false,
+        })
+    }
+
+    /// Wait up to `timeout` for the device to become readable.
+    fn wait_readable(&self, timeout: Duration) -> Result<bool, String> {
+        let mut fds = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) };
+        if ready < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(format!("Unable to wait for accelerometer data: {}", e));
+        }
+        Ok(ready > 0)
+    }
+}
+
+impl AccelerometerSource for IioBufferSource {
+    fn read_sample(&mut self) -> Result<Sample, String> {
+        // Hand out the sysfs reading first
+        if !self.started {
+            self.started = true;
+            return Ok(self.last_sample);
+        }
+
+        // Sensors that only report changes may stay silent for a long time,
+        // in which case the last known reading is still valid.
+        if !self.wait_readable(self.interval)? {
+            return Ok(self.last_sample);
+        }
+
+        let read = self
+            .file
+            .read(&mut self.buffer[self.pending..])
+            .map_err(|e| format!("Unable to read accelerometer buffer: {}", e))?;
+        self.pending += read;
+
+        let scan_bytes = self.layout.scan_bytes;
+        let complete = self.pending / scan_bytes;
+        if complete > 0 {
+            let latest = &self.buffer[(complete - 1) * scan_bytes..complete * scan_bytes];
+            let [x, y, z] = self.layout.decode(latest);
+            self.last_sample = self.calibration.sample([x as f32, y as f32, z as f32]);
+            if let Some(nanos) = self.layout.decode_timestamp(latest) {
+                self.last_sample.timestamp = scan_instant(self.clock, nanos);
+            }
+            self.buffer
+                .copy_within(complete * scan_bytes..self.pending, 0);
+            self.pending -= complete * scan_bytes;
+        }
+
+        Ok(self.last_sample)
+    }
+}
+
+/// Undoes the buffer setup when dropped, also when `IioBufferSource::new`
+/// fails halfway: disables the buffer, then the scan elements and the
+/// trigger that were turned on for it.
+struct BufferSetup {
+    device: PathBuf,
+    enabled: Vec<PathBuf>,
+    trigger: bool,
+}
+
+impl Drop for BufferSetup {
+    fn drop(&mut self) {
+        let _ = write_attr(&self.device.join("buffer/enable"), "0");
+        for path in &self.enabled {
+            let _ = write_attr(path, "0");
+        }
+        if self.trigger {
+            let _ = write_attr(&self.device.join("trigger/current_trigger"), "\n");
+        }
+    }
+}
+
+/// The clock `in_timestamp` is taken from, as set in `current_timestamp_clock`.
+fn timestamp_clock(device: &Path) -> libc::clockid_t {
+    match read_attr(&device.join("current_timestamp_clock")).as_deref() {
+        Ok("monotonic") => libc::CLOCK_MONOTONIC,
+        Ok("monotonic_raw") => libc::CLOCK_MONOTONIC_RAW,
+        Ok("realtime_coarse") => libc::CLOCK_REALTIME_COARSE,
+        Ok("monotonic_coarse") => libc::CLOCK_MONOTONIC_COARSE,
+        Ok("boottime") => libc::CLOCK_BOOTTIME,
+        Ok("tai") => libc::CLOCK_TAI,
+        _ => libc::CLOCK_REALTIME,
+    }
+}
+
+/// Turn a scan timestamp in nanoseconds on `clock` into an `Instant`, by
+/// its age on that clock.
+fn scan_instant(clock: libc::clockid_t, nanos: i64) -> Instant {
+    let now = Instant::now();
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(clock, &mut time) } != 0 {
+        return now;
+    }
+    // Both fields are narrower on 32 bit targets
+    #[allow(clippy::unnecessary_cast)]
+    let clock_now = time.tv_sec as i64 * 1_000_000_000 + time.tv_nsec as i64;
+    let age = clock_now.saturating_sub(nanos).max(0) as u64;
+    now.checked_sub(Duration::from_nanos(age)).unwrap_or(now)
+}
+
+/// Collect the name, index and type of every enabled scan element.
+fn enabled_channels(device: &Path) -> Result<Vec<(String, u32, ScanType)>, String> {
+    let scan_elements = device.join("scan_elements");
+    let entries = fs::read_dir(&scan_elements)
+        .map_err(|e| format!("Unable to list {}: {}", scan_elements.display(), e))?;
+
+    let mut channels = vec![];
+    for entry in entries.flatten() {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let name = match file_name.strip_suffix("_en") {
+            Some(name) => name,
+            None => continue,
+        };
+        if read_attr(&entry.path())? != "1" {
+            continue;
+        }
+        let index = read_attr(&scan_elements.join(format!("{}_index", name)))?
+            .parse::<u32>()
+            .map_err(|_| format!("Invalid scan index for {}", name))?;
+        let scan_type = read_attr(&scan_elements.join(format!("{}_type", name)))?.parse()?;
+        channels.push((name.to_string(), index, scan_type));
+    }
+    Ok(channels)
+}
+
+/// Attach the device's own data-ready trigger, unless one is already set.
+/// Tells whether it was attached.
+fn set_trigger(device: &Path) -> Result<bool, String> {
+    let current = device.join("trigger/current_trigger");
+    if !current.exists() || !read_attr(&current)?.is_empty() {
+        return Ok(false);
+    }
+
+    let name = read_attr(&device.join("name"))?;
+    let number = device_number(device);
+    let trigger_name = format!("{}-dev{}", name, number);
+
+    for trigger in glob("/sys/bus/iio/devices/trigger*/name")
+        .unwrap()
+        .flatten()
+    {
+        if read_attr(&trigger)? == trigger_name {
+            return write_attr(&current, &trigger_name).map(|_| true);
+        }
+    }
+    Err(format!(
+        "No trigger '{}' found for accelerometer",
+        trigger_name
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(name: &str, index: u32, scan_type: &str) -> (String, u32, ScanType) {
+        (name.to_string(), index, scan_type.parse().unwrap())
+    }
+
+    #[test]
+    fn parse_scan_types() {
+        assert_eq!(
+            "le:s12/16>>4".parse(),
+            Ok(ScanType {
+                big_endian: false,
+                signed: true,
+                bits: 12,
+                storage_bits: 16,
+                repeat: 1,
+                shift: 4,
+            })
+        );
+        assert_eq!(
+            "be:u24/32X2>>0\n".parse(),
+            Ok(ScanType {
+                big_endian: true,
+                signed: false,
+                bits: 24,
+                storage_bits: 32,
+                repeat: 2,
+                shift: 0,
+            })
+        );
+        assert!("le:s12/12>>4".parse::<ScanType>().is_err());
+        assert!("xe:s16/16>>0".parse::<ScanType>().is_err());
+        assert!("le:f16/16>>0".parse::<ScanType>().is_err());
+        assert!("le:s16/16".parse::<ScanType>().is_err());
+    }
+
+    #[test]
+    fn decode_shifted_signed_values() {
+        let scan_type: ScanType = "le:s12/16>>4".parse().unwrap();
+        // -1 and 2047 left-aligned in 16 bits
+        assert_eq!(scan_type.decode(&[0xf0, 0xff]), -1);
+        assert_eq!(scan_type.decode(&[0xf0, 0x7f]), 2047);
+        assert_eq!(scan_type.decode(&[0x00, 0x80]), -2048);
+
+        let scan_type: ScanType = "be:s16/16>>0".parse().unwrap();
+        assert_eq!(scan_type.decode(&[0xfe, 0x0c]), -500);
+
+        let scan_type: ScanType = "le:u16/16>>0".parse().unwrap();
+        assert_eq!(scan_type.decode(&[0x0c, 0xfe]), 0xfe0c);
+    }
+
+    // The scans below are synthetic, built after the channel layouts these
+    // drivers expose in sysfs.
+
+    /// cros-ec-accel: three s16 axes followed by a 64 bit timestamp.
+    #[test]
+    fn cros_ec_synthetic_scan() {
+        let layout = ScanLayout::new(&[
+            channel("in_accel_x", 0, "le:s16/16>>0"),
+            channel("in_accel_y", 1, "le:s16/16>>0"),
+            channel("in_accel_z", 2, "le:s16/16>>0"),
+            channel("in_timestamp", 3, "le:s64/64>>0"),
+        ])
+        .unwrap();
+        assert_eq!(layout.scan_bytes, 16);
+
+        let scan = [
+            0x1a, 0x00, 0x30, 0xc0, 0x64, 0x01, 0x00, 0x00, // x, y, z, padding
+            0x00, 0x2c, 0xbb, 0x5d, 0x1c, 0x00, 0x00, 0x00, // timestamp
+        ];
+        assert_eq!(layout.decode(&scan), [26, -16336, 356]);
+        assert_eq!(layout.decode_timestamp(&scan), Some(121_831_631_872));
+    }
+
+    /// hid-sensor-accel-3d: three s32 axes, padding, 64 bit timestamp.
+    #[test]
+    fn hid_sensor_synthetic_scan() {
+        let layout = ScanLayout::new(&[
+            channel("in_timestamp", 3, "le:s64/64>>0"),
+            channel("in_accel_z", 2, "le:s32/32>>0"),
+            channel("in_accel_x", 0, "le:s32/32>>0"),
+            channel("in_accel_y", 1, "le:s32/32>>0"),
+        ])
+        .unwrap();
+        assert_eq!(layout.scan_bytes, 24);
+
+        let scan = [
+            0x9c, 0xff, 0xff, 0xff, // x = -100
+            0xd8, 0x29, 0xf1, 0xff, // y = -972328
+            0x10, 0x27, 0x00, 0x00, // z = 10000
+            0x00, 0x00, 0x00, 0x00, // padding
+            0x40, 0x1f, 0x7a, 0x03, 0x25, 0x00, 0x00, 0x00, // timestamp
+        ];
+        assert_eq!(layout.decode(&scan), [-100, -972328, 10000]);
+        assert_eq!(layout.decode_timestamp(&scan), Some(158_972_124_992));
+    }
+
+    /// bmc150: left-aligned 12 bit samples, no timestamp.
+    #[test]
+    fn bmc150_synthetic_scan() {
+        let layout = ScanLayout::new(&[
+            channel("in_accel_x", 0, "le:s12/16>>4"),
+            channel("in_accel_y", 1, "le:s12/16>>4"),
+            channel("in_accel_z", 2, "le:s12/16>>4"),
+        ])
+        .unwrap();
+        assert_eq!(layout.scan_bytes, 6);
+
+        let scan = [0x10, 0x00, 0x00, 0xc0, 0xf0, 0xff];
+        assert_eq!(layout.decode(&scan), [1, -1024, -1]);
+        assert_eq!(layout.decode_timestamp(&scan), None);
+    }
+
+    #[test]
+    fn scan_timestamps_become_instants() {
+        let mut time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+        #[allow(clippy::unnecessary_cast)]
+        let nanos = time.tv_sec as i64 * 1_000_000_000 + time.tv_nsec as i64;
+
+        let age = scan_instant(libc::CLOCK_MONOTONIC, nanos - 200_000_000).elapsed();
+        assert!(age >= Duration::from_millis(200) && age < Duration::from_secs(2));
+        // Timestamps from the future are taken as now
+        assert!(
+            scan_instant(libc::CLOCK_MONOTONIC, nanos + 1_000_000_000).elapsed()
+                < Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn missing_axis_is_rejected() {
+        assert!(ScanLayout::new(&[
+            channel("in_accel_x", 0, "le:s16/16>>0"),
+            channel("in_accel_y", 1, "le:s16/16>>0"),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn failed_setup_is_rolled_back() {
+        let device = std::env::temp_dir().join(format!("rot8-iio-{}", std::process::id()));
+        fs::create_dir_all(device.join("scan_elements")).unwrap();
+        fs::create_dir_all(device.join("buffer")).unwrap();
+        fs::write(device.join("buffer/enable"), "0").unwrap();
+        fs::write(device.join("scan_elements/in_accel_x_en"), "0").unwrap();
+        fs::write(device.join("scan_elements/in_accel_y_en"), "1").unwrap();
+        fs::write(device.join("scan_elements/in_accel_z_en"), "0").unwrap();
+        // No *_index or *_type attributes, so reading the layout fails
+
+        assert!(IioBufferSource::new(&device, Duration::from_millis(100)).is_err());
+        let attr = |name: &str| read_attr(&device.join(name)).unwrap();
+        assert_eq!(attr("scan_elements/in_accel_x_en"), "0");
+        assert_eq!(attr("scan_elements/in_accel_y_en"), "1");
+        assert_eq!(attr("scan_elements/in_accel_z_en"), "0");
+        assert_eq!(attr("buffer/enable"), "0");
+
+        fs::remove_dir_all(&device).unwrap();
+    }
+}
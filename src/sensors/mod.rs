@@ -0,0 +1,51 @@
+use std::time::Instant;
+
+/// Standard gravity in m/s².
+pub const STANDARD_GRAVITY: f32 = 9.80665;
+
+/// A single 3-axis accelerometer reading, in the sensor's own axes.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    /// Whether the values are in m/s² rather than raw, unscaled counts.
+    pub physical: bool,
+    /// When the sensor took the reading.
+    pub timestamp: Instant,
+}
+
+pub trait AccelerometerSource {
+    /// Wait for the next reading and return it.
+    fn read_sample(&mut self) -> Result<Sample, String>;
+}
+
+/// Replays a fixed list of readings, for testing the orientation logic.
+#[cfg(test)]
+pub struct FakeSource {
+    samples: std::vec::IntoIter<Sample>,
+}
+
+#[cfg(test)]
+impl FakeSource {
+    pub fn new(samples: Vec<Sample>) -> Self {
+        FakeSource {
+            samples: samples.into_iter(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl AccelerometerSource for FakeSource {
+    fn read_sample(&mut self) -> Result<Sample, String> {
+        self.samples
+            .next()
+            .ok_or_else(|| "No more samples".to_string())
+    }
+}
+
+pub mod hinge;
+pub mod hwdb;
+pub mod iio;
+pub mod iio_buffer;
+pub mod sensor_proxy;
@@ -0,0 +1,254 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use zbus::blocking::Connection;
+use zbus::dbus_proxy;
+
+use super::{AccelerometerSource, Sample, STANDARD_GRAVITY};
+
+#[dbus_proxy(
+    interface = "net.hadess.SensorProxy",
+    default_service = "net.hadess.SensorProxy",
+    default_path = "/net/hadess/SensorProxy"
+)]
+trait SensorProxy {
+    fn claim_accelerometer(&self) -> zbus::Result<()>;
+
+    fn release_accelerometer(&self) -> zbus::Result<()>;
+
+    #[dbus_proxy(property)]
+    fn has_accelerometer(&self) -> zbus::Result<bool>;
+
+    #[dbus_proxy(property)]
+    fn accelerometer_orientation(&self) -> zbus::Result<String>;
+}
+
+/// Reads the accelerometer orientation from iio-sensor-proxy over D-Bus.
+///
+/// iio-sensor-proxy only reports a coarse orientation, so each one is turned
+/// back into the gravity vector the sysfs source would have produced for it.
+/// Orientation changes reach the proxy's property cache through signals, and
+/// the cache is read once per interval.
+pub struct SensorProxySource {
+    proxy: SensorProxyProxyBlocking<'static>,
+    interval: Duration,
+    last_read: Option<Instant>,
+}
+
+impl SensorProxySource {
+    /// Claim the accelerometer from the service on `connection`, normally
+    /// the system bus.
+    pub fn new(connection: &Connection, interval: Duration) -> Result<Self, String> {
+        let proxy = SensorProxyProxyBlocking::new(connection)
+            .map_err(|e| format!("Unable to reach iio-sensor-proxy: {}", e))?;
+
+        if !proxy
+            .has_accelerometer()
+            .map_err(|e| format!("Unable to query iio-sensor-proxy: {}", e))?
+        {
+            return Err("iio-sensor-proxy reports no accelerometer".to_string());
+        }
+        proxy
+            .claim_accelerometer()
+            .map_err(|e| format!("Unable to claim the accelerometer: {}", e))?;
+
+        Ok(SensorProxySource {
+            proxy,
+            interval,
+            last_read: None,
+        })
+    }
+}
+
+impl AccelerometerSource for SensorProxySource {
+    fn read_sample(&mut self) -> Result<Sample, String> {
+        if let Some(last_read) = self.last_read {
+            let elapsed = last_read.elapsed();
+            if elapsed < self.interval {
+                thread::sleep(self.interval - elapsed);
+            }
+        }
+        self.last_read = Some(Instant::now());
+
+        let orientation = self
+            .proxy
+            .accelerometer_orientation()
+            .map_err(|e| format!("Unable to read orientation: {}", e))?;
+        let (x, y, z) = match orientation.as_str() {
+            "normal" => (0., -1., 0.),
+            "bottom-up" => (0., 1., 0.),
+            "left-up" => (1., 0., 0.),
+            "right-up" => (-1., 0., 0.),
+            // "undefined": lying flat, which matches no orientation
+            _ => (0., 0., -1.),
+        };
+        Ok(Sample {
+            x: x * STANDARD_GRAVITY,
+            y: y * STANDARD_GRAVITY,
+            z: z * STANDARD_GRAVITY,
+            physical: true,
+            timestamp: Instant::now(),
+        })
+    }
+}
+
+impl Drop for SensorProxySource {
+    fn drop(&mut self) {
+        let _ = self.proxy.release_accelerometer();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use zbus::blocking::ConnectionBuilder;
+    use zbus::dbus_interface;
+
+    /// A private bus, shut down when dropped.
+    struct Bus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl Bus {
+        fn start() -> Option<Bus> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take()?)
+                .read_line(&mut address)
+                .ok()?;
+            Some(Bus {
+                daemon,
+                address: address.trim().to_string(),
+            })
+        }
+
+        fn connect(&self) -> ConnectionBuilder<'static> {
+            ConnectionBuilder::address(self.address.as_str()).unwrap()
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    /// Stands in for iio-sensor-proxy.
+    struct FakeSensorProxy {
+        orientation: String,
+        claimed: bool,
+    }
+
+    #[dbus_interface(name = "net.hadess.SensorProxy")]
+    impl FakeSensorProxy {
+        fn claim_accelerometer(&mut self) {
+            self.claimed = true;
+        }
+
+        fn release_accelerometer(&mut self) {
+            self.claimed = false;
+        }
+
+        #[dbus_interface(property)]
+        fn has_accelerometer(&self) -> bool {
+            true
+        }
+
+        #[dbus_interface(property)]
+        fn accelerometer_orientation(&self) -> String {
+            self.orientation.clone()
+        }
+    }
+
+    const PATH: &str = "/net/hadess/SensorProxy";
+
+    fn set_orientation(service: &Connection, orientation: &str) {
+        let iface = service
+            .object_server()
+            .interface::<_, FakeSensorProxy>(PATH)
+            .unwrap();
+        iface.get_mut().orientation = orientation.to_string();
+        zbus::block_on(
+            iface
+                .get()
+                .accelerometer_orientation_changed(iface.signal_context()),
+        )
+        .unwrap();
+    }
+
+    fn claimed(service: &Connection) -> bool {
+        let iface = service
+            .object_server()
+            .interface::<_, FakeSensorProxy>(PATH)
+            .unwrap();
+        let claimed = iface.get().claimed;
+        claimed
+    }
+
+    /// Read until the source reports the given vector, as signals arrive
+    /// asynchronously.
+    fn read_until(source: &mut SensorProxySource, expected: (f32, f32, f32)) -> bool {
+        (0..50).any(|_| {
+            let sample = source.read_sample().unwrap();
+            (
+                sample.x / STANDARD_GRAVITY,
+                sample.y / STANDARD_GRAVITY,
+                sample.z / STANDARD_GRAVITY,
+            ) == expected
+        })
+    }
+
+    #[test]
+    fn follows_orientation_changes() {
+        let bus = match Bus::start() {
+            Some(bus) => bus,
+            None => {
+                eprintln!("dbus-daemon is not available, skipping");
+                return;
+            }
+        };
+        let service = bus
+            .connect()
+            .name("net.hadess.SensorProxy")
+            .unwrap()
+            .serve_at(
+                PATH,
+                FakeSensorProxy {
+                    orientation: "normal".to_string(),
+                    claimed: false,
+                },
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        let client = bus.connect().build().unwrap();
+
+        let mut source = SensorProxySource::new(&client, Duration::from_millis(10)).unwrap();
+        assert!(claimed(&service));
+        assert!(read_until(&mut source, (0., -1., 0.)));
+
+        for (orientation, expected) in [
+            ("left-up", (1., 0., 0.)),
+            ("bottom-up", (0., 1., 0.)),
+            ("right-up", (-1., 0., 0.)),
+            ("undefined", (0., 0., -1.)),
+            ("normal", (0., -1., 0.)),
+        ]
+        .iter()
+        {
+            set_orientation(&service, orientation);
+            assert!(read_until(&mut source, *expected), "{}", orientation);
+        }
+
+        drop(source);
+        assert!(!claimed(&service));
+    }
+}
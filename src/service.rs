@@ -0,0 +1,116 @@
+use zbus::blocking::{Connection, ConnectionBuilder};
+use zbus::dbus_interface;
+
+const SERVICE_NAME: &str = "net.hadess.SensorProxy";
+const OBJECT_PATH: &str = "/net/hadess/SensorProxy";
+
+/// Publishes the orientation computed by rot8 as a stand-in for iio-sensor-proxy,
+/// so that other applications asking `net.hadess.SensorProxy` keep working.
+pub struct SensorProxyService {
+    connection: Connection,
+    orientation: &'static str,
+}
+
+impl SensorProxyService {
+    pub fn new() -> Result<Self, String> {
+        let connection = ConnectionBuilder::system()
+            .and_then(|builder| builder.name(SERVICE_NAME))
+            .and_then(|builder| {
+                builder.serve_at(
+                    OBJECT_PATH,
+                    SensorProxyInterface {
+                        orientation: "undefined",
+                    },
+                )
+            })
+            .and_then(|builder| builder.build())
+            .map_err(|e| {
+                format!(
+                    "Unable to publish {} on the system bus: {}",
+                    SERVICE_NAME, e
+                )
+            })?;
+
+        Ok(SensorProxyService {
+            connection,
+            orientation: "undefined",
+        })
+    }
+
+    /// Update the published `AccelerometerOrientation`, notifying listeners on change.
+    pub fn set_orientation(&mut self, orientation: &'static str) -> Result<(), String> {
+        if orientation == self.orientation {
+            return Ok(());
+        }
+
+        let iface = self
+            .connection
+            .object_server()
+            .interface::<_, SensorProxyInterface>(OBJECT_PATH)
+            .map_err(|e| format!("Sensor proxy interface is gone: {}", e))?;
+        let mut proxy = iface.get_mut();
+        proxy.orientation = orientation;
+        zbus::block_on(proxy.accelerometer_orientation_changed(iface.signal_context()))
+            .map_err(|e| format!("Unable to signal orientation change: {}", e))?;
+
+        self.orientation = orientation;
+        Ok(())
+    }
+}
+
+struct SensorProxyInterface {
+    orientation: &'static str,
+}
+
+/// The subset of iio-sensor-proxy's interface that rot8 can serve. Light and
+/// proximity sensors are reported as absent.
+#[dbus_interface(name = "net.hadess.SensorProxy")]
+impl SensorProxyInterface {
+    /// Readings are always available, so claiming is a no-op.
+    fn claim_accelerometer(&self) {}
+
+    fn release_accelerometer(&self) {}
+
+    fn claim_light(&self) {}
+
+    fn release_light(&self) {}
+
+    fn claim_proximity(&self) {}
+
+    fn release_proximity(&self) {}
+
+    #[dbus_interface(property)]
+    fn has_accelerometer(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn accelerometer_orientation(&self) -> &str {
+        self.orientation
+    }
+
+    #[dbus_interface(property)]
+    fn has_ambient_light(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn light_level_unit(&self) -> &str {
+        "lux"
+    }
+
+    #[dbus_interface(property)]
+    fn light_level(&self) -> f64 {
+        0.
+    }
+
+    #[dbus_interface(property)]
+    fn has_proximity(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn proximity_near(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,170 @@
+use std::io::ErrorKind;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+use evdev::{Device, EventType, InputEvent, SwitchType};
+use wayland_client::protocol::wl_output::Transform;
+
+use crate::posture::Posture;
+
+/// An input device reporting `SW_TABLET_MODE`, usually from the firmware of
+/// a convertible (e.g. intel-vbtn or a Chromebook's cros_ec).
+pub struct TabletModeSwitch {
+    device: Device,
+    path: PathBuf,
+    active: bool,
+}
+
+impl TabletModeSwitch {
+    /// Find the first input device with a tablet mode switch.
+    pub fn find() -> Option<Self> {
+        let mut devices: Vec<(PathBuf, Device)> = evdev::enumerate()
+            .filter(|(_, device)| {
+                device
+                    .supported_switches()
+                    .is_some_and(|switches| switches.contains(SwitchType::SW_TABLET_MODE))
+            })
+            .collect();
+        devices.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let (path, device) = devices.into_iter().next()?;
+        println!(
+            "Using tablet mode switch {} ({})",
+            path.display(),
+            device.name().unwrap_or_default()
+        );
+        // Events are drained on every update, which must not block
+        let fd = device.as_raw_fd();
+        if unsafe {
+            libc::fcntl(
+                fd,
+                libc::F_SETFL,
+                libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK,
+            )
+        } < 0
+        {
+            eprintln!(
+                "Unable to read tablet mode switch {}: {}",
+                path.display(),
+                std::io::Error::last_os_error()
+            );
+            return None;
+        }
+        let active = device
+            .get_switch_state()
+            .map(|state| state.contains(SwitchType::SW_TABLET_MODE))
+            .unwrap_or(false);
+        Some(TabletModeSwitch {
+            device,
+            path,
+            active,
+        })
+    }
+
+    /// Apply the switch events received since the last call and return the
+    /// current state.
+    pub fn is_active(&mut self) -> Result<bool, String> {
+        match self.device.fetch_events() {
+            Ok(events) => self.active = switch_state(self.active, events),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => {
+                return Err(format!(
+                    "Unable to read tablet mode switch {}: {}",
+                    self.path.display(),
+                    e
+                ))
+            }
+        }
+        Ok(self.active)
+    }
+}
+
+/// The tablet mode switch state after a stream of input events.
+fn switch_state(active: bool, events: impl IntoIterator<Item = InputEvent>) -> bool {
+    events
+        .into_iter()
+        .filter(|event| {
+            event.event_type() == EventType::SWITCH && event.code() == SwitchType::SW_TABLET_MODE.0
+        })
+        .fold(active, |_, event| event.value() != 0)
+}
+
+/// Decide whether the device is in tablet mode. A hardware signal (the switch
+/// or a detached keyboard) is authoritative, then the posture; without
+/// either, any rotation away from normal counts.
+pub fn tablet_mode(switch: Option<bool>, posture: Option<Posture>, orientation: Transform) -> bool {
+    match (switch, posture) {
+        (Some(active), _) => active,
+        (None, Some(posture)) => posture.is_tablet_like(),
+        (None, None) => orientation != Transform::Normal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(type_: EventType, code: u16, value: i32) -> InputEvent {
+        InputEvent::new(type_, code, value)
+    }
+
+    #[test]
+    fn follows_switch_events() {
+        let tablet = SwitchType::SW_TABLET_MODE.0;
+        let syn = event(EventType::SYNCHRONIZATION, 0, 0);
+
+        assert!(switch_state(
+            false,
+            [event(EventType::SWITCH, tablet, 1), syn]
+        ));
+        assert!(!switch_state(
+            true,
+            [event(EventType::SWITCH, tablet, 0), syn]
+        ));
+        // The last event wins
+        assert!(switch_state(
+            false,
+            [
+                event(EventType::SWITCH, tablet, 0),
+                syn,
+                event(EventType::SWITCH, tablet, 1),
+                syn
+            ]
+        ));
+        assert!(switch_state(true, []));
+    }
+
+    #[test]
+    fn ignores_other_events() {
+        let lid = SwitchType::SW_LID.0;
+        assert!(!switch_state(
+            false,
+            [
+                event(EventType::SWITCH, lid, 1),
+                event(EventType::KEY, SwitchType::SW_TABLET_MODE.0, 1)
+            ]
+        ));
+    }
+
+    #[test]
+    fn switch_overrides_orientation_and_posture() {
+        assert!(tablet_mode(
+            Some(true),
+            Some(Posture::Laptop),
+            Transform::Normal
+        ));
+        assert!(!tablet_mode(
+            Some(false),
+            Some(Posture::Tablet),
+            Transform::_90
+        ));
+    }
+
+    #[test]
+    fn falls_back_to_posture_then_orientation() {
+        assert!(tablet_mode(None, Some(Posture::Tent), Transform::_180));
+        assert!(!tablet_mode(None, Some(Posture::Laptop), Transform::_90));
+        assert!(tablet_mode(None, None, Transform::_270));
+        assert!(!tablet_mode(None, None, Transform::Normal));
+    }
+}